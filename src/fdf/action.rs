@@ -0,0 +1,564 @@
+use super::cache::mtime_ns;
+use super::interrupt::is_interrupted;
+use super::journal::{write_journal, Journal, JournalEntry};
+use super::options::{Action, LinkMode};
+use super::output::{FileResult, HashGroupResult, KeyGroupResult};
+use super::reflink::{dedupe_file, ReflinkOutcome};
+use super::report_path::ReportPath;
+use humansize::{format_size, DECIMAL};
+use std::collections::HashSet;
+use std::fs;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct ActionStats {
+    pub n_groups: u64,
+    pub n_files: u64,
+    pub n_skipped: u64,
+    pub n_failed: u64,
+    pub n_bytes_reclaimed: u64,
+}
+
+/// What happened to a single non-kept file.
+enum FileOutcome {
+    /// The file was replaced; the flag tells whether its data was freed.
+    Done(bool),
+    /// There was nothing to do.
+    Skipped(String),
+}
+
+/// Get a temporary path next to `path`, so renaming it over `path`
+/// stays within the same directory (and thus filesystem).
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".fdf-tmp-{}", std::process::id()));
+    path.with_file_name(name)
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_a: &Metadata, _b: &Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn is_same_device(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev()
+}
+
+#[cfg(not(unix))]
+fn is_same_device(_a: &Metadata, _b: &Metadata) -> bool {
+    true
+}
+
+/// Whether removing this path would free the file's data.
+#[cfg(unix)]
+fn is_last_link(meta: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink() <= 1
+}
+
+#[cfg(not(unix))]
+fn is_last_link(_meta: &Metadata) -> bool {
+    true
+}
+
+/// Replace `path` with a hardlink to `keeper`.
+///
+/// The link is first created under a temporary name and then renamed
+/// over `path`, so `path` always refers to either the old or the new file.
+fn replace_with_hardlink(keeper: &Path, path: &Path) -> io::Result<()> {
+    let temp_path = temp_path_for(path);
+    fs::hard_link(keeper, &temp_path)?;
+    if let Err(e) = fs::rename(&temp_path, path) {
+        fs::remove_file(&temp_path).ok();
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symlinks are only supported on Unix",
+    ))
+}
+
+/// Replace `path` with a symlink to the absolute path of `keeper`,
+/// via a temporary name like `replace_with_hardlink`.
+fn replace_with_symlink(keeper: &Path, path: &Path) -> io::Result<()> {
+    let temp_path = temp_path_for(path);
+    symlink(&fs::canonicalize(keeper)?, &temp_path)?;
+    if let Err(e) = fs::rename(&temp_path, path) {
+        fs::remove_file(&temp_path).ok();
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Make `path` share `keeper`'s data on a copy-on-write filesystem,
+/// keeping its own inode and metadata.
+fn replace_with_reflink(keeper: &Path, path: &Path, size: u64) -> io::Result<FileOutcome> {
+    Ok(match dedupe_file(keeper, path, size)? {
+        ReflinkOutcome::Deduplicated => FileOutcome::Done(true),
+        ReflinkOutcome::AlreadyShared => {
+            FileOutcome::Skipped("already sharing extents".to_string())
+        }
+    })
+}
+
+/// Whether a file still has the size and mtime it was scanned with.
+fn is_as_scanned(meta: &Metadata, size: u64, mtime_ns_scanned: Option<i64>) -> bool {
+    meta.len() == size && meta.modified().ok().map(mtime_ns) == mtime_ns_scanned
+}
+
+/// Check that the kept file is still there, as it was scanned.
+fn check_keeper(keeper: &Keeper, size: u64) -> io::Result<Metadata> {
+    let meta = fs::symlink_metadata(keeper.path)?;
+    if !is_as_scanned(&meta, size, keeper.mtime_ns) {
+        return Err(io::Error::other("kept file has changed since scanning"));
+    }
+    Ok(meta)
+}
+
+/// Check that `file` can still be linked to the kept file.
+fn check_linkable(
+    mode: &LinkMode,
+    keeper: &Keeper,
+    file: &FileResult,
+    size: u64,
+) -> io::Result<Result<Metadata, FileOutcome>> {
+    let keeper_meta = check_keeper(keeper, size)?;
+    let meta = fs::symlink_metadata(&file.path)?;
+    if is_same_file(&keeper_meta, &meta) {
+        let reason = match mode {
+            LinkMode::Symlink => "same file as the kept file",
+            LinkMode::Hard | LinkMode::Reflink => "already linked",
+        };
+        return Ok(Err(FileOutcome::Skipped(reason.to_string())));
+    }
+    if *mode == LinkMode::Hard && !is_same_device(&keeper_meta, &meta) {
+        return Err(io::Error::other(
+            "not on the same filesystem as the kept file",
+        ));
+    }
+    if !is_as_scanned(&meta, size, file.mtime_ns) {
+        return Ok(Err(FileOutcome::Skipped(
+            "changed since scanning".to_string(),
+        )));
+    }
+    Ok(Ok(meta))
+}
+
+/// Check that `file` and the kept file still look like the files that were scanned.
+fn check_unchanged(
+    keeper: &Keeper,
+    file: &FileResult,
+    size: u64,
+) -> io::Result<Result<Metadata, FileOutcome>> {
+    let keeper_meta = check_keeper(keeper, size)?;
+    let meta = fs::symlink_metadata(&file.path)?;
+    if is_same_file(&keeper_meta, &meta) {
+        return Ok(Err(FileOutcome::Skipped(
+            "same file as the kept file".to_string(),
+        )));
+    }
+    if !is_as_scanned(&meta, size, file.mtime_ns) {
+        return Ok(Err(FileOutcome::Skipped(
+            "changed since scanning".to_string(),
+        )));
+    }
+    Ok(Ok(meta))
+}
+
+/// Copy `from` to `to` (via a temporary file), then remove `from`.
+fn copy_and_remove(from: &Path, to: &Path) -> io::Result<()> {
+    let temp_path = temp_path_for(to);
+    let res = fs::copy(from, &temp_path).and_then(|_| {
+        let f = fs::OpenOptions::new().write(true).open(&temp_path)?;
+        if let Ok(mtime) = fs::metadata(from).and_then(|meta| meta.modified()) {
+            f.set_modified(mtime)?;
+        }
+        f.sync_all()?;
+        fs::rename(&temp_path, to)
+    });
+    if res.is_err() {
+        fs::remove_file(&temp_path).ok();
+    }
+    res?;
+    fs::remove_file(from)
+}
+
+/// Move a file, creating the destination's parent directories as needed
+/// and falling back to copying when moving across filesystems.
+///
+/// Refuses to overwrite an existing file.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if to.symlink_metadata().is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        ));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => copy_and_remove(from, to),
+        res => res,
+    }
+}
+
+/// A non-kept file of a duplicate group, and where it's going to be moved if applicable.
+struct PlannedFile<'a> {
+    file: &'a FileResult,
+    destination: Option<PathBuf>,
+}
+
+/// The file kept of a duplicate group, and its mtime when scanned.
+struct Keeper<'a> {
+    path: &'a ReportPath,
+    mtime_ns: Option<i64>,
+}
+
+struct GroupPlan<'a> {
+    size: u64,
+    hg: &'a HashGroupResult,
+    keeper: Keeper<'a>,
+    files: Vec<PlannedFile<'a>>,
+}
+
+/// Find a free path under `dir` for `file`, mirroring its path relative to
+/// its root, or its whole path if it has none.
+fn move_destination(dir: &Path, file: &FileResult, taken: &mut HashSet<PathBuf>) -> PathBuf {
+    let path: &Path = &file.path;
+    let relative: PathBuf = file
+        .root
+        .as_ref()
+        .and_then(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    let mut destination = dir.join(&relative);
+    let mut n = 1;
+    while taken.contains(&destination) || destination.symlink_metadata().is_ok() {
+        let mut name = relative.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", n));
+        destination = dir.join(relative.with_file_name(name));
+        n += 1;
+    }
+    taken.insert(destination.clone());
+    destination
+}
+
+fn plan_actions<'a>(
+    key_group_results: &'a [KeyGroupResult],
+    action: &Action,
+) -> Vec<GroupPlan<'a>> {
+    let mut taken = HashSet::new();
+    let mut plans = Vec::new();
+    for kgr in key_group_results {
+        for hg in &kgr.hash_groups {
+            if !hg.verified || hg.files.len() <= 1 {
+                continue;
+            }
+            let keeper = hg.keeper.as_ref().unwrap_or(&hg.files[0].path);
+            let keeper_mtime_ns = hg
+                .files
+                .iter()
+                .find(|file| file.path == *keeper)
+                .and_then(|file| file.mtime_ns);
+            let files = hg
+                .files
+                .iter()
+                .filter(|file| file.path != *keeper && !file.reference)
+                .map(|file| PlannedFile {
+                    file,
+                    destination: match action {
+                        Action::Move(dir) => Some(move_destination(dir, file, &mut taken)),
+                        _ => None,
+                    },
+                })
+                .collect();
+            plans.push(GroupPlan {
+                size: kgr.size,
+                hg,
+                keeper: Keeper {
+                    path: keeper,
+                    mtime_ns: keeper_mtime_ns,
+                },
+                files,
+            });
+        }
+    }
+    plans
+}
+
+fn act_on_file(
+    action: &Action,
+    keeper: &Keeper,
+    planned: &PlannedFile,
+    size: u64,
+    dry_run: bool,
+) -> io::Result<FileOutcome> {
+    let file = planned.file;
+    let checked = match action {
+        Action::Link(mode) => check_linkable(mode, keeper, file, size)?,
+        Action::Delete | Action::Move(_) => check_unchanged(keeper, file, size)?,
+    };
+    let meta = match checked {
+        Ok(meta) => meta,
+        Err(outcome) => return Ok(outcome),
+    };
+    if dry_run {
+        match &planned.destination {
+            Some(destination) => eprintln!(
+                "    would {} {} to {}",
+                action.verb(),
+                file.path.display(),
+                destination.display()
+            ),
+            None => eprintln!("    would {} {}", action.verb(), file.path.display()),
+        }
+        return Ok(FileOutcome::Done(is_last_link(&meta)));
+    }
+    let path: &Path = &file.path;
+    let keeper: &Path = keeper.path;
+    match action {
+        Action::Link(LinkMode::Hard) => replace_with_hardlink(keeper, path)?,
+        Action::Link(LinkMode::Symlink) => replace_with_symlink(keeper, path)?,
+        Action::Link(LinkMode::Reflink) => return replace_with_reflink(keeper, path, size),
+        Action::Delete => fs::remove_file(path)?,
+        Action::Move(_) => move_file(path, planned.destination.as_ref().unwrap())?,
+    }
+    Ok(FileOutcome::Done(is_last_link(&meta)))
+}
+
+fn act_on_hash_group(plan: &GroupPlan, action: &Action, dry_run: bool, stats: &mut ActionStats) {
+    let size = plan.size;
+    // The other files may be the only copies left of what the kept file held.
+    if let Err(e) = check_keeper(&plan.keeper, size) {
+        eprintln!(
+            "[!] Skipping group {} as {}: {}",
+            plan.hg.hash,
+            plan.keeper.path.display(),
+            e
+        );
+        stats.n_skipped += plan.files.len() as u64;
+        return;
+    }
+    let mut n_done: u64 = 0;
+    let mut n_skipped: u64 = 0;
+    let mut n_bytes: u64 = 0;
+    for planned in &plan.files {
+        match act_on_file(action, &plan.keeper, planned, size, dry_run) {
+            Ok(FileOutcome::Done(freed)) => {
+                n_done += 1;
+                if freed {
+                    n_bytes += size;
+                }
+            }
+            Ok(FileOutcome::Skipped(reason)) => {
+                n_skipped += 1;
+                eprintln!("    skipped {}: {}", planned.file.path.display(), reason);
+            }
+            Err(e) => {
+                stats.n_failed += 1;
+                eprintln!(
+                    "[!] Unable to {} {}: {}",
+                    action.verb(),
+                    planned.file.path.display(),
+                    e
+                );
+            }
+        }
+    }
+    let (done, reclaimed) = if dry_run {
+        (format!("would {}", action.verb()), "would reclaim")
+    } else {
+        (action.past_verb().to_string(), "reclaimed")
+    };
+    eprintln!(
+        "{}: kept {}, {} {} files ({} skipped), {} {}.",
+        plan.hg.hash,
+        plan.keeper.path.display(),
+        done,
+        n_done,
+        n_skipped,
+        reclaimed,
+        format_size(n_bytes, DECIMAL),
+    );
+    stats.n_groups += 1;
+    stats.n_skipped += n_skipped;
+    stats.n_files += n_done;
+    stats.n_bytes_reclaimed += n_bytes;
+}
+
+fn write_action_journal(plans: &[GroupPlan], action: &Action, path: &Path) -> io::Result<()> {
+    let entries = plans
+        .iter()
+        .flat_map(|plan| {
+            plan.files.iter().map(move |planned| JournalEntry {
+                path: planned.file.path.clone(),
+                size: plan.size,
+                mtime_ns: planned.file.mtime_ns,
+                digest: plan.hg.hash.clone(),
+                keeper: plan.keeper.path.clone(),
+                keeper_mtime_ns: plan.keeper.mtime_ns,
+                destination: planned.destination.as_deref().map(ReportPath::new),
+            })
+        })
+        .collect();
+    write_journal(path, &Journal::new(action.verb(), entries))
+}
+
+/// Act on all but the kept file of each verified duplicate group.
+///
+/// Destructive actions first record what they're about to do in a journal;
+/// if that can't be written, nothing is done.
+pub fn act_on_duplicates(
+    key_group_results: &[KeyGroupResult],
+    action: &Action,
+    dry_run: bool,
+    journal_path: &Path,
+) -> anyhow::Result<ActionStats> {
+    let plans = plan_actions(key_group_results, action);
+    if action.needs_journal() && !dry_run {
+        write_action_journal(&plans, action, journal_path).map_err(|e| {
+            anyhow::anyhow!("Unable to write journal {}: {}", journal_path.display(), e)
+        })?;
+        eprintln!("Wrote journal to {}.", journal_path.display());
+    }
+    let mut stats = ActionStats::default();
+    for plan in &plans {
+        if is_interrupted() {
+            eprintln!("Interrupted; leaving the remaining groups alone.");
+            break;
+        }
+        act_on_hash_group(plan, action, dry_run, &mut stats);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    /// A key group of the given files, all with the contents `data`, keeping the first.
+    fn duplicates(root: &Path, names: &[&str], data: &str) -> KeyGroupResult {
+        let files = names
+            .iter()
+            .map(|name| {
+                let path = root.join(name);
+                fs::write(&path, data).unwrap();
+                FileResult::for_test(&path, Some(root))
+            })
+            .collect();
+        KeyGroupResult::for_test(data.len() as u64, vec![files])
+    }
+
+    /// Overwrite a file without changing its size, as an edit after scanning might.
+    fn edit_keeping_size(path: &Path) {
+        let len = fs::metadata(path).unwrap().len() as usize;
+        let mtime = fs::metadata(path).unwrap().modified().unwrap();
+        let mut f = fs::OpenOptions::new().write(true).open(path).unwrap();
+        f.write_all(&vec![b'x'; len]).unwrap();
+        f.set_modified(mtime + Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn groups_whose_keeper_was_edited_are_left_alone() {
+        let root = tempdir().unwrap();
+        let journal = root.path().join("journal.json");
+        let kgr = duplicates(root.path(), &["a.txt", "b.txt", "c.txt"], "data");
+        edit_keeping_size(&root.path().join("a.txt"));
+        let stats = act_on_duplicates(&[kgr], &Action::Delete, false, &journal).unwrap();
+        assert_eq!((stats.n_files, stats.n_skipped), (0, 2));
+        assert_eq!(fs::read(root.path().join("b.txt")).unwrap(), b"data");
+        assert_eq!(fs::read(root.path().join("c.txt")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn files_edited_since_scanning_are_not_linked() {
+        let root = tempdir().unwrap();
+        let journal = root.path().join("journal.json");
+        let kgr = duplicates(root.path(), &["a.txt", "b.txt"], "data");
+        edit_keeping_size(&root.path().join("b.txt"));
+        let action = Action::Link(LinkMode::Hard);
+        let stats = act_on_duplicates(&[kgr], &action, false, &journal).unwrap();
+        assert_eq!((stats.n_files, stats.n_skipped), (0, 1));
+        assert_eq!(fs::read(root.path().join("b.txt")).unwrap(), b"xxxx");
+    }
+
+    #[test]
+    fn move_destination_avoids_collisions() {
+        let root = tempdir().unwrap();
+        let quarantine = tempdir().unwrap();
+        let path = root.path().join("sub/file.txt");
+        let file = FileResult {
+            mtime_ns: None,
+            ..FileResult::for_test(&path, Some(root.path()))
+        };
+        fs::create_dir_all(quarantine.path().join("sub")).unwrap();
+        fs::write(quarantine.path().join("sub/file.txt"), "taken").unwrap();
+        let mut taken = HashSet::new();
+        assert_eq!(
+            move_destination(quarantine.path(), &file, &mut taken),
+            quarantine.path().join("sub/file.txt.1")
+        );
+        assert_eq!(
+            move_destination(quarantine.path(), &file, &mut taken),
+            quarantine.path().join("sub/file.txt.2")
+        );
+    }
+
+    #[test]
+    fn move_file_creates_parents_and_refuses_to_overwrite() {
+        let dir = tempdir().unwrap();
+        let from = dir.path().join("a");
+        let to = dir.path().join("x/y/a");
+        fs::write(&from, "data").unwrap();
+        move_file(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"data");
+
+        fs::write(&from, "other").unwrap();
+        let err = move_file(&from, &to).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&from).unwrap(), b"other");
+        assert_eq!(fs::read(&to).unwrap(), b"data");
+    }
+
+    #[test]
+    fn copy_and_remove_keeps_contents_and_mtime() {
+        let dir = tempdir().unwrap();
+        let from = dir.path().join("a");
+        let to = dir.path().join("b");
+        fs::write(&from, "data").unwrap();
+        let mtime = fs::metadata(&from).unwrap().modified().unwrap();
+        copy_and_remove(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert!(!temp_path_for(&to).exists());
+        assert_eq!(fs::read(&to).unwrap(), b"data");
+        assert_eq!(fs::metadata(&to).unwrap().modified().unwrap(), mtime);
+    }
+}
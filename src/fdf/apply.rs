@@ -0,0 +1,133 @@
+use super::action::{act_on_duplicates, ActionStats};
+use super::hash::hash_path;
+use super::hasher::HashAlgorithm;
+use super::options::{ApplyOptions, IoMode};
+use super::output::{GrandResult, HashGroupResult, REPORT_VERSION};
+use super::verify::files_equal;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+pub fn read_report(path: &Path) -> anyhow::Result<GrandResult> {
+    let reader = BufReader::new(File::open(path)?);
+    let report: GrandResult = serde_json::from_reader(reader)?;
+    if report.version != REPORT_VERSION {
+        anyhow::bail!("unsupported report version {}", report.version);
+    }
+    Ok(report)
+}
+
+/// Check whether a file still has the size and digest it had when scanned.
+fn is_unchanged(
+    path: &Path,
+    size: u64,
+    hash: &str,
+    algorithm: &HashAlgorithm,
+    key: Option<&[u8; 32]>,
+    hash_bytes: u64,
+    io_mode: IoMode,
+) -> bool {
+    let res = fs::symlink_metadata(path)
+        .map_err(|e| e.into())
+        .and_then(|meta| {
+            if meta.len() != size {
+                return Ok(false);
+            }
+            hash_path(path, size, algorithm, key, hash_bytes, io_mode).map(|digest| digest == hash)
+        });
+    match res {
+        Ok(true) => true,
+        Ok(false) => {
+            eprintln!("    skipped {}: changed since scanning", path.display());
+            false
+        }
+        Err(e) => {
+            eprintln!("[!] Unable to check {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+/// Drop files from a hash group that no longer match the report.
+///
+/// Groups whose kept file has changed are marked unverified so they're left alone.
+/// Files are compared byte-for-byte with the kept file if the group wasn't
+/// verified when scanning, or if the digests only cover part of the files.
+fn revalidate_group(
+    hg: &mut HashGroupResult,
+    size: u64,
+    algorithm: &HashAlgorithm,
+    key: Option<&[u8; 32]>,
+    hash_bytes: u64,
+    io_mode: IoMode,
+) {
+    let keeper = hg
+        .keeper
+        .clone()
+        .unwrap_or_else(|| hg.files[0].path.clone());
+    hg.files.retain(|file| {
+        is_unchanged(
+            &file.path, size, &hg.hash, algorithm, key, hash_bytes, io_mode,
+        )
+    });
+    if !hg.files.iter().any(|file| file.path == keeper) {
+        eprintln!(
+            "[!] Kept file {} has changed; skipping its group",
+            keeper.display()
+        );
+        hg.verified = false;
+        return;
+    }
+    if !hg.verified || hash_bytes < size {
+        let keeper_path: &Path = &keeper;
+        hg.files.retain(|file| {
+            file.path == keeper
+                || match files_equal(keeper_path, &file.path) {
+                    Ok(true) => true,
+                    Ok(false) => {
+                        eprintln!(
+                            "    skipped {}: differs from the kept file",
+                            file.path.display()
+                        );
+                        false
+                    }
+                    Err(e) => {
+                        eprintln!("[!] Unable to verify {}: {}", file.path.display(), e);
+                        false
+                    }
+                }
+        });
+        hg.verified = true;
+    }
+    hg.keeper = Some(keeper);
+}
+
+/// Act on the duplicates listed in a previously written report,
+/// after making sure the files haven't changed since.
+pub fn apply(options: &ApplyOptions) -> anyhow::Result<ActionStats> {
+    let mut report = read_report(Path::new(&options.report))
+        .map_err(|e| anyhow::anyhow!("Unable to read report {}: {}", options.report, e))?;
+    if report.hash_algorithm.keyed && options.hash_key.is_none() {
+        anyhow::bail!("The report uses blake3-keyed digests; pass the same --hash-key to apply it");
+    }
+    let key = options.hash_key.as_ref();
+    for kgr in report.key_groups.iter_mut() {
+        for hg in kgr.hash_groups.iter_mut().filter(|hg| hg.files.len() > 1) {
+            revalidate_group(
+                hg,
+                kgr.size,
+                &report.hash_algorithm,
+                key,
+                report.hash_bytes,
+                options.io_mode,
+            );
+        }
+    }
+    act_on_duplicates(
+        &report.key_groups,
+        &options.action,
+        options.dry_run,
+        Path::new(&options.journal),
+    )
+}
@@ -0,0 +1,213 @@
+use super::find::{AugDirEntry, FileId};
+use super::report_path::ReportPath;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CACHE_VERSION: u32 = 2;
+
+/// Identifies a digest of a given byte range of a given file version.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub mtime_ns: i64,
+    pub algorithm: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    #[serde(flatten)]
+    key: CacheKey,
+    path: ReportPath,
+    digest: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: Vec<CacheEntry>,
+}
+
+#[derive(Debug)]
+struct CacheValue {
+    path: ReportPath,
+    digest: String,
+    /// Whether the entry was looked up or stored during this run,
+    /// i.e. is known to be up to date.
+    touched: bool,
+}
+
+pub fn mtime_ns(mtime: SystemTime) -> i64 {
+    match mtime.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+impl CacheKey {
+    /// Build a cache key for a range of an entry; `None` if the platform
+    /// doesn't give us enough metadata to reliably identify the file.
+    pub fn new(
+        dent: &AugDirEntry,
+        algorithm: String,
+        offset: u64,
+        length: u64,
+    ) -> Option<CacheKey> {
+        let id = dent.id?;
+        let mtime = dent.mtime?;
+        Some(CacheKey {
+            dev: id.dev,
+            ino: id.ino,
+            size: dent.size,
+            mtime_ns: mtime_ns(mtime),
+            algorithm,
+            offset,
+            length,
+        })
+    }
+
+    fn matches_file(&self, path: &Path) -> bool {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+        FileId::from_metadata(&metadata).is_some_and(|id| id.dev == self.dev && id.ino == self.ino)
+            && metadata.len() == self.size
+            && metadata.modified().map(mtime_ns).ok() == Some(self.mtime_ns)
+    }
+}
+
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<CacheKey, CacheValue>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+fn read_cache_file(path: &Path) -> anyhow::Result<CacheFile> {
+    let reader = BufReader::new(File::open(path)?);
+    let cache_file: CacheFile = serde_json::from_reader(reader)?;
+    if cache_file.version != CACHE_VERSION {
+        anyhow::bail!("unsupported cache version {}", cache_file.version);
+    }
+    Ok(cache_file)
+}
+
+impl HashCache {
+    /// Load the cache from `path`. A missing or unreadable cache file
+    /// results in an empty cache.
+    pub fn load(path: &Path) -> HashCache {
+        let entries = if path.exists() {
+            match read_cache_file(path) {
+                Ok(cache_file) => cache_file.entries,
+                Err(e) => {
+                    eprintln!(
+                        "[!] Ignoring unreadable hash cache {}: {}",
+                        path.display(),
+                        e
+                    );
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        HashCache {
+            path: path.to_path_buf(),
+            entries: Mutex::new(
+                entries
+                    .into_iter()
+                    .map(|ent| {
+                        (
+                            ent.key,
+                            CacheValue {
+                                path: ent.path,
+                                digest: ent.digest,
+                                touched: false,
+                            },
+                        )
+                    })
+                    .collect(),
+            ),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(key) {
+            Some(value) => {
+                value.touched = true;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value.digest.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: CacheKey, path: &Path, digest: &str) {
+        self.entries.lock().unwrap().insert(
+            key,
+            CacheValue {
+                path: ReportPath::new(path),
+                digest: digest.to_string(),
+                touched: true,
+            },
+        );
+    }
+
+    pub fn n_hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn n_misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Write the cache back to disk, pruning entries whose files
+    /// have since disappeared or changed.
+    ///
+    /// Returns the number of entries pruned.
+    pub fn save(&self) -> io::Result<u64> {
+        let entries = self.entries.lock().unwrap();
+        let mut n_pruned = 0;
+        let mut cache_file = CacheFile {
+            version: CACHE_VERSION,
+            entries: Vec::with_capacity(entries.len()),
+        };
+        for (key, value) in entries.iter() {
+            if !value.touched && !key.matches_file(&value.path) {
+                n_pruned += 1;
+                continue;
+            }
+            cache_file.entries.push(CacheEntry {
+                key: key.clone(),
+                path: value.path.clone(),
+                digest: value.digest.clone(),
+            });
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &cache_file)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(n_pruned)
+    }
+}
@@ -1,9 +1,23 @@
-use super::options::{HashAlgorithm, NameGroupingOption, Options, ReportOption};
+use super::globs::GlobFilter;
+use super::hasher;
+use super::hasher::HashAlgorithm;
+use super::journal::default_journal_name;
+use super::options::{
+    Action, ActionKind, ApplyOptions, DuplicateDirsOption, HardlinkOption, Invocation, IoMode,
+    KeepRule, LinkMode, NameGroupingOption, Options, PermFilter, ReportOption, RestoreOptions,
+    SymlinkOption,
+};
 use super::parse_size::parse_size_string;
-use clap::{command, value_parser, Arg, ArgAction, ArgMatches};
+use super::parse_time::parse_time_string;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{command, value_parser, Arg, ArgAction, ArgGroup, ArgMatches, Command};
 
 use regex;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
 use std::result::Result;
+use std::time::SystemTime;
 
 fn read_report_option(args: &ArgMatches, name: &str) -> ReportOption {
     match args.get_one::<String>(name) {
@@ -16,24 +30,225 @@ fn read_report_option(args: &ArgMatches, name: &str) -> ReportOption {
     }
 }
 
-fn parse_regex_set(args: &ArgMatches, name: &str) -> Result<regex::RegexSet, regex::Error> {
-    regex::RegexSet::new(args.get_many::<String>(name).unwrap_or_default())
+/// Regexes are matched against the raw bytes of paths, so they work for non-UTF-8 paths too.
+fn parse_regex_set(args: &ArgMatches, name: &str) -> Result<regex::bytes::RegexSet, regex::Error> {
+    regex::bytes::RegexSet::new(args.get_many::<String>(name).unwrap_or_default())
 }
 
 fn parse_size(value: &str) -> anyhow::Result<u64, String> {
     parse_size_string(value).map_err(|e| e.to_string())
 }
 
-pub fn parse_args() -> anyhow::Result<Options> {
+fn parse_time(value: &str) -> anyhow::Result<SystemTime, String> {
+    parse_time_string(value).map_err(|e| e.to_string())
+}
+
+/// Parse a find(1)-style octal mode: `MODE` for exact, `-MODE` for all bits, `/MODE` for any bit.
+fn parse_perm(value: &str) -> Result<PermFilter, String> {
+    let (make, octal): (fn(u32) -> PermFilter, &str) = match value.as_bytes().first() {
+        Some(b'-') => (PermFilter::All, &value[1..]),
+        Some(b'/') => (PermFilter::Any, &value[1..]),
+        _ => (PermFilter::Exact, value),
+    };
+    match u32::from_str_radix(octal, 8) {
+        Ok(bits) if bits <= 0o7777 => Ok(make(bits)),
+        _ => Err(format!("Invalid octal mode: {}", value)),
+    }
+}
+
+fn parse_similarity(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(similarity) if (0.0..=1.0).contains(&similarity) => Ok(similarity),
+        _ => Err(format!("Invalid similarity (expected 0 to 1): {}", value)),
+    }
+}
+
+/// Parse a 256-bit key for keyed BLAKE3, given as 64 hex digits.
+fn parse_hash_key(value: &str) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(value, &mut key)
+        .map_err(|_| "Expected a key of 64 hex digits".to_string())?;
+    Ok(key)
+}
+
+fn io_mode_arg() -> Arg {
+    Arg::new("io-mode")
+        .long("io-mode")
+        .value_parser(value_parser!(IoMode))
+        .default_value("auto")
+        .help("How to read files for hashing")
+}
+
+fn hash_key_arg() -> Arg {
+    Arg::new("hash-key")
+        .long("hash-key")
+        .value_name("HEX")
+        .value_parser(parse_hash_key)
+        .help("Key for --hash-algorithm blake3-keyed, as 64 hex digits")
+}
+
+/// Arguments for choosing what to do with duplicates, shared by the main command and `apply`.
+fn action_args() -> Vec<Arg> {
+    vec![
+        Arg::new("link")
+            .long("link")
+            .value_parser(value_parser!(LinkMode))
+            .required(false)
+            .help("Replace duplicates with links to a kept file (implies --verify)"),
+        Arg::new("delete")
+            .long("delete")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("link")
+            .help("Delete duplicates, keeping one file of each group (implies --verify)"),
+        Arg::new("move-to")
+            .long("move-to")
+            .value_name("DIRECTORY")
+            .value_parser(value_parser!(PathBuf))
+            .conflicts_with_all(["link", "delete"])
+            .help("Move duplicates into this directory, keeping one file of each group (implies --verify)"),
+        Arg::new("dry-run")
+            .long("dry-run")
+            .short('n')
+            .action(ArgAction::SetTrue)
+            .requires("action")
+            .help("Only print what --link, --delete or --move-to would do"),
+        Arg::new("journal")
+            .long("journal")
+            .value_name("FILE")
+            .help("New file to record deleted or moved files in before acting on them [default: fdf-journal-<timestamp>.json]"),
+    ]
+}
+
+/// Express each `--unique-under` directory the way found paths are: under
+/// the roots containing it, or canonical for `--files-from`, which has none.
+fn resolve_unique_under(args: &ArgMatches, roots: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let no_roots = roots.iter().any(|root| root.as_os_str().is_empty());
+    let mut resolved = Vec::new();
+    for dir in args.get_many::<PathBuf>("unique-under").unwrap_or_default() {
+        let canonical = fs::canonicalize(dir).map_err(|e| {
+            anyhow::anyhow!("Unable to resolve --unique-under {}: {}", dir.display(), e)
+        })?;
+        if no_roots {
+            resolved.push(canonical);
+            continue;
+        }
+        let n_resolved = resolved.len();
+        for root in roots {
+            let Ok(canonical_root) = fs::canonicalize(root) else {
+                continue;
+            };
+            if let Ok(relative) = canonical.strip_prefix(&canonical_root) {
+                resolved.push(root.join(relative));
+            }
+        }
+        if resolved.len() == n_resolved {
+            anyhow::bail!(
+                "--unique-under {} is not under any searched directory",
+                dir.display()
+            );
+        }
+    }
+    Ok(resolved)
+}
+
+fn read_journal_option(args: &ArgMatches) -> String {
+    args.get_one::<String>("journal")
+        .cloned()
+        .unwrap_or_else(default_journal_name)
+}
+
+fn read_action(args: &ArgMatches) -> Option<Action> {
+    if args.get_flag("delete") {
+        Some(Action::Delete)
+    } else if let Some(dir) = args.get_one::<PathBuf>("move-to") {
+        Some(Action::Move(dir.clone()))
+    } else {
+        args.get_one::<LinkMode>("link").cloned().map(Action::Link)
+    }
+}
+
+pub fn parse_args() -> anyhow::Result<Invocation> {
+    parse_args_from(std::env::args_os())
+}
+
+/// Parse the given command line, starting with the program name.
+pub fn parse_args_from<I, T>(args: I) -> anyhow::Result<Invocation>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
     let matches = command!()
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("apply")
+                .about("Act on the duplicates listed in a JSON report written by --output-json")
+                .arg(
+                    Arg::new("report")
+                        .value_name("REPORT")
+                        .required(true)
+                        .help("JSON report file"),
+                )
+                .arg(hash_key_arg())
+                .arg(io_mode_arg())
+                .args(action_args())
+                .group(
+                    ArgGroup::new("action")
+                        .args(["link", "delete", "move-to"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Undo a --delete or --move-to run using its journal")
+                .arg(
+                    Arg::new("journal")
+                        .value_name("JOURNAL")
+                        .required(true)
+                        .help("Journal file written by the run to undo"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .short('n')
+                        .action(ArgAction::SetTrue)
+                        .help("Only print what would be restored"),
+                ),
+        )
         .arg(
             Arg::new("directory")
                 .long("directory")
                 .short('d')
                 .action(ArgAction::Append)
                 .value_name("DIRECTORY")
+                .value_parser(value_parser!(PathBuf))
                 .help("Add directory to search")
-                .required(true),
+                .required_unless_present("files-from"),
+        )
+        .arg(
+            Arg::new("reference")
+                .long("reference")
+                .short('r')
+                .action(ArgAction::Append)
+                .value_name("DIRECTORY")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with("files-from")
+                .help("Add a directory whose files are only compared against, never reported alone or acted on"),
+        )
+        .arg(
+            Arg::new("files-from")
+                .long("files-from")
+                .value_name("FILE")
+                .conflicts_with("directory")
+                .help("Read paths to consider from FILE (- for stdin) instead of searching directories"),
+        )
+        .arg(
+            Arg::new("null")
+                .long("null")
+                .short('0')
+                .action(ArgAction::SetTrue)
+                .requires("files-from")
+                .help("Paths read with --files-from are separated by NULs instead of newlines"),
         )
         .arg(
             Arg::new("v")
@@ -53,14 +268,33 @@ pub fn parse_args() -> anyhow::Result<Options> {
                 .default_value("18446744073709551615")
                 .hide_default_value(true),
         )
+        .arg(
+            Arg::new("prefix-bytes")
+                .long("prefix-bytes")
+                .help("Hash N first bytes as a pre-filtering stage (0 to disable)")
+                .value_parser(parse_size)
+                .default_value("4096"),
+        )
+        .arg(
+            Arg::new("suffix-bytes")
+                .long("suffix-bytes")
+                .help("Hash N last bytes as a pre-filtering stage (0 to disable)")
+                .value_parser(parse_size)
+                .default_value("4096"),
+        )
         .arg(
             Arg::new("hash-algorithm")
                 .long("hash-algorithm")
                 .short('a')
                 .help("Select a hash algorithm; there are speed/quality tradeoffs")
-                .value_parser(value_parser!(HashAlgorithm))
+                .value_parser(
+                    PossibleValuesParser::new(hasher::names())
+                        .map(|name| hasher::lookup(&name).unwrap()),
+                )
                 .default_value("sha256"),
         )
+        .arg(hash_key_arg())
+        .arg(io_mode_arg())
         .arg(
             Arg::new("name-grouping")
                 .long("name-grouping")
@@ -88,6 +322,35 @@ pub fn parse_args() -> anyhow::Result<Options> {
                 .alias("ol")
                 .help("Output list of files matched (to stdout or the given filename)"),
         )
+        .arg(
+            Arg::new("report-unique")
+                .long("output-unique")
+                .required(false)
+                .alias("unique")
+                .help("Output list of files with no duplicates (to stdout or the given filename)"),
+        )
+        .arg(
+            Arg::new("unique-under")
+                .long("unique-under")
+                .action(ArgAction::Append)
+                .value_name("DIRECTORY")
+                .value_parser(value_parser!(PathBuf))
+                .help("Only list unique files under this directory; may be repeated"),
+        )
+        .arg(
+            Arg::new("report-script")
+                .long("output-script")
+                .required(false)
+                .alias("os")
+                .help("Output a shell script to deduplicate files (to stdout or the given filename)"),
+        )
+        .arg(
+            Arg::new("script-action")
+                .long("script-action")
+                .value_parser(value_parser!(ActionKind))
+                .default_value("delete")
+                .help("What the script written by --output-script does with duplicates"),
+        )
         .arg(
             Arg::new("dir-exclude-re")
                 .long("dir-exclude-re")
@@ -125,6 +388,28 @@ pub fn parse_args() -> anyhow::Result<Options> {
                 .required(false)
                 .help("Regexp to include files with"),
         )
+        .arg(
+            Arg::new("glob")
+                .long("glob")
+                .short('g')
+                .action(ArgAction::Append)
+                .value_name("GLOB")
+                .help("Gitignore-style glob to include files with, relative to the directory root"),
+        )
+        .arg(
+            Arg::new("exclude-glob")
+                .long("exclude-glob")
+                .short('G')
+                .action(ArgAction::Append)
+                .value_name("GLOB")
+                .help("Gitignore-style glob to exclude files and directories with, relative to the directory root"),
+        )
+        .arg(
+            Arg::new("respect-ignore")
+                .long("respect-ignore")
+                .action(ArgAction::SetTrue)
+                .help("Skip files listed in .ignore and .fdfignore files, and in .gitignore files inside git repositories"),
+        )
         .arg(
             Arg::new("min-size")
                 .long("min-size")
@@ -143,31 +428,271 @@ pub fn parse_args() -> anyhow::Result<Options> {
                 .default_value("18446744073709551615")
                 .hide_default_value(true),
         )
-        .get_matches();
-    Ok(Options {
-        directories: matches
-            .get_many::<String>("directory")
+        .arg(
+            Arg::new("older-than")
+                .long("older-than")
+                .value_name("WHEN")
+                .value_parser(parse_time)
+                .help("Only consider files modified before WHEN (e.g. 30d, 2024-01-01)"),
+        )
+        .arg(
+            Arg::new("newer-than")
+                .long("newer-than")
+                .value_name("WHEN")
+                .value_parser(parse_time)
+                .help("Only consider files modified after WHEN (e.g. 12h, 2024-01-01)"),
+        )
+        .arg(
+            Arg::new("changed-before")
+                .long("changed-before")
+                .value_name("WHEN")
+                .value_parser(parse_time)
+                .help("Only consider files whose status changed (ctime) before WHEN"),
+        )
+        .arg(
+            Arg::new("changed-after")
+                .long("changed-after")
+                .value_name("WHEN")
+                .value_parser(parse_time)
+                .help("Only consider files whose status changed (ctime) after WHEN"),
+        )
+        .arg(
+            Arg::new("uid")
+                .long("uid")
+                .value_parser(value_parser!(u32))
+                .help("Only consider files owned by this user id"),
+        )
+        .arg(
+            Arg::new("gid")
+                .long("gid")
+                .value_parser(value_parser!(u32))
+                .help("Only consider files owned by this group id"),
+        )
+        .arg(
+            Arg::new("perm")
+                .long("perm")
+                .value_name("MODE")
+                .value_parser(parse_perm)
+                .allow_hyphen_values(true)
+                .help("Only consider files with these octal permission bits: MODE exactly, -MODE all of, /MODE any of"),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .action(ArgAction::SetTrue)
+                .help("Compare duplicates byte-for-byte before reporting them"),
+        )
+        .arg(
+            Arg::new("hash-cache")
+                .long("hash-cache")
+                .value_name("FILE")
+                .required(false)
+                .help("Cache digests in the given file across runs"),
+        )
+        .arg(
+            Arg::new("hardlinks")
+                .long("hardlinks")
+                .value_parser(value_parser!(HardlinkOption))
+                .default_value("list")
+                .help("List hardlinked files in their own report section, or leave that section out (each file is hashed once either way)"),
+        )
+        .arg(
+            Arg::new("symlinks")
+                .long("symlinks")
+                .value_parser(value_parser!(SymlinkOption))
+                .default_value("skip")
+                .help("Skip symlinks, follow them, or report those pointing at found files"),
+        )
+        .arg(
+            Arg::new("one-file-system")
+                .long("one-file-system")
+                .action(ArgAction::SetTrue)
+                .help("Don't descend into directories on other filesystems than their root"),
+        )
+        .arg(
+            Arg::new("exclude-fs-type")
+                .long("exclude-fs-type")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_name("TYPE")
+                .help("Skip filesystems of these types, e.g. proc,sysfs,nfs,fuse (Linux only)"),
+        )
+        .arg(
+            Arg::new("duplicate-dirs")
+                .long("duplicate-dirs")
+                .value_parser(value_parser!(DuplicateDirsOption))
+                .default_value("off")
+                .help("Also report directories whose found files are all duplicates, or report them instead of the files"),
+        )
+        .arg(
+            Arg::new("dir-similarity")
+                .long("dir-similarity")
+                .value_parser(parse_similarity)
+                .default_value("0.9")
+                .help("Also report directories sharing at least this fraction of their bytes (1 for only identical ones)"),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .short('j')
+                .value_parser(value_parser!(usize))
+                .default_value("0")
+                .help("Number of threads to walk directories with (0 to pick automatically)"),
+        )
+        .args(action_args())
+        .group(ArgGroup::new("action").args(["link", "delete", "move-to"]))
+        .arg(
+            Arg::new("keep")
+                .long("keep")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(KeepRule))
+                .required(false)
+                .help("Rule for choosing the file to keep in each group; may be repeated to break ties"),
+        )
+        .arg(
+            Arg::new("keep-under")
+                .long("keep-under")
+                .action(ArgAction::Append)
+                .value_name("DIRECTORY")
+                .value_parser(value_parser!(PathBuf))
+                .required(false)
+                .help("Prefer keeping files under this directory; may be repeated in order of preference"),
+        )
+        .get_matches_from(args);
+    match matches.subcommand() {
+        Some(("apply", sub_matches)) => {
+            return Ok(Invocation::Apply(ApplyOptions {
+                report: sub_matches.get_one::<String>("report").unwrap().clone(),
+                hash_key: sub_matches.get_one::<[u8; 32]>("hash-key").copied(),
+                io_mode: *sub_matches.get_one::<IoMode>("io-mode").unwrap(),
+                action: read_action(sub_matches).unwrap(),
+                dry_run: sub_matches.get_flag("dry-run"),
+                journal: read_journal_option(sub_matches),
+            }));
+        }
+        Some(("restore", sub_matches)) => {
+            return Ok(Invocation::Restore(RestoreOptions {
+                journal: sub_matches.get_one::<String>("journal").unwrap().clone(),
+                dry_run: sub_matches.get_flag("dry-run"),
+            }));
+        }
+        _ => {}
+    }
+    let action = read_action(&matches);
+    let hash_algorithm = *matches.get_one::<HashAlgorithm>("hash-algorithm").unwrap();
+    let hash_key = matches.get_one::<[u8; 32]>("hash-key").copied();
+    if hash_algorithm.keyed && hash_key.is_none() {
+        anyhow::bail!(
+            "--hash-algorithm {} requires --hash-key",
+            hash_algorithm.name
+        );
+    }
+    let files_from = matches.get_one::<String>("files-from").cloned();
+    let reference_dirs: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("reference")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let directories: Vec<PathBuf> = if files_from.is_some() {
+        vec![PathBuf::new()]
+    } else {
+        matches
+            .get_many::<PathBuf>("directory")
             .unwrap_or_default()
+            .chain(&reference_dirs)
             .cloned()
-            .collect(),
+            .collect()
+    };
+    let unique_under = resolve_unique_under(&matches, &directories)?;
+    let include_globs: Vec<String> = matches
+        .get_many::<String>("glob")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let exclude_globs: Vec<String> = matches
+        .get_many::<String>("exclude-glob")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let globs = directories
+        .iter()
+        .map(|dir| GlobFilter::new(dir, &include_globs, &exclude_globs))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Invocation::Find(Box::new(Options {
+        directories,
+        n_reference: reference_dirs.len(),
+        files_from,
+        null_separated: matches.get_flag("null"),
         dir_exclude_regexes: parse_regex_set(&matches, "dir-exclude-re")?,
         dir_include_regexes: parse_regex_set(&matches, "dir-include-re")?,
         file_exclude_regexes: parse_regex_set(&matches, "file-exclude-re")?,
         file_include_regexes: parse_regex_set(&matches, "file-include-re")?,
+        globs,
         verbosity: *matches.get_one::<u64>("v").unwrap(),
         hash_bytes: *matches.get_one::<u64>("hash-bytes").unwrap(),
-        hash_algorithm: matches
-            .get_one::<HashAlgorithm>("hash-algorithm")
-            .unwrap()
-            .clone(),
+        prefix_bytes: *matches.get_one::<u64>("prefix-bytes").unwrap(),
+        suffix_bytes: *matches.get_one::<u64>("suffix-bytes").unwrap(),
+        hash_algorithm,
+        hash_key,
+        io_mode: *matches.get_one::<IoMode>("io-mode").unwrap(),
         report_human: read_report_option(&matches, "report-human"),
         report_json: read_report_option(&matches, "report-json"),
         report_file_list: read_report_option(&matches, "report-file-list"),
+        report_unique: read_report_option(&matches, "report-unique"),
+        unique_under,
+        report_script: read_report_option(&matches, "report-script"),
+        script_action: matches
+            .get_one::<ActionKind>("script-action")
+            .unwrap()
+            .clone(),
         name_grouping: matches
             .get_one::<NameGroupingOption>("name-grouping")
             .unwrap()
             .clone(),
         min_size: *matches.get_one::<u64>("min-size").unwrap(),
         max_size: *matches.get_one::<u64>("max-size").unwrap(),
-    })
+        older_than: matches.get_one::<SystemTime>("older-than").copied(),
+        newer_than: matches.get_one::<SystemTime>("newer-than").copied(),
+        changed_before: matches.get_one::<SystemTime>("changed-before").copied(),
+        changed_after: matches.get_one::<SystemTime>("changed-after").copied(),
+        uid: matches.get_one::<u32>("uid").copied(),
+        gid: matches.get_one::<u32>("gid").copied(),
+        perm: matches.get_one::<PermFilter>("perm").cloned(),
+        verify: matches.get_flag("verify") || action.is_some(),
+        hash_cache: matches.get_one::<String>("hash-cache").cloned(),
+        hardlinks: matches
+            .get_one::<HardlinkOption>("hardlinks")
+            .unwrap()
+            .clone(),
+        symlinks: matches
+            .get_one::<SymlinkOption>("symlinks")
+            .unwrap()
+            .clone(),
+        duplicate_dirs: matches
+            .get_one::<DuplicateDirsOption>("duplicate-dirs")
+            .unwrap()
+            .clone(),
+        dir_similarity: *matches.get_one::<f64>("dir-similarity").unwrap(),
+        one_file_system: matches.get_flag("one-file-system"),
+        threads: *matches.get_one::<usize>("threads").unwrap(),
+        ignore_files: matches.get_flag("respect-ignore"),
+        exclude_fs_types: matches
+            .get_many::<String>("exclude-fs-type")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        action,
+        dry_run: matches.get_flag("dry-run"),
+        journal: read_journal_option(&matches),
+        keep_rules: matches
+            .get_many::<KeepRule>("keep")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        keep_under: matches
+            .get_many::<PathBuf>("keep-under")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+    })))
 }
@@ -0,0 +1,410 @@
+use super::find::{AugDirEntry, KeyToDentsMap};
+use super::options::Options;
+use super::output::{DirGroupResult, DirResult, HashGroupResult, KeyGroupResult};
+use super::report_path::{path_to_bytes, ReportPath};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+type Digest = [u8; 32];
+
+/// Files with more copies than this are left out when looking for similar
+/// directories, as comparing every pair of them would take too long.
+const MAX_SIMILARITY_GROUP: usize = 64;
+
+/// A directory containing found files, with digests computed bottom-up
+/// from its children's names and digests, like a Merkle tree.
+#[derive(Default)]
+struct DirNode<'a> {
+    /// Name, whether it is a directory, and digest of each child.
+    children: Vec<(Cow<'a, [u8]>, bool, Digest)>,
+    /// Total size of the found files in the tree.
+    size: u64,
+    n_files: u64,
+    digest: Digest,
+}
+
+/// Digest standing in for the contents of the files in a hash group.
+fn group_digest(kgr: &KeyGroupResult, hg: &HashGroupResult) -> Digest {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&kgr.size.to_le_bytes());
+    hasher.update(kgr.identifier.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(hg.hash.as_bytes());
+    hasher.update(&(hg.part.unwrap_or(0) as u64).to_le_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+/// Digest for a file with no duplicate, which only needs to differ from all others.
+fn unique_digest(path: &Path) -> Digest {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"unique\0");
+    hasher.update(&path_to_bytes(path));
+    *hasher.finalize().as_bytes()
+}
+
+fn dir_digest(children: &mut [(Cow<[u8]>, bool, Digest)]) -> Digest {
+    children.sort_unstable();
+    let mut hasher = blake3::Hasher::new();
+    for (name, is_dir, digest) in children.iter() {
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name);
+        hasher.update(&[*is_dir as u8]);
+        hasher.update(digest);
+    }
+    *hasher.finalize().as_bytes()
+}
+
+/// Path, size and digest of every found file, including all of its hardlinks.
+///
+/// Only files sharing a hash group with others get a digest of its hash;
+/// the rest are unique, so each gets a digest of its own.
+fn file_digests<'a>(
+    by_key: &'a KeyToDentsMap,
+    culled: &'a [AugDirEntry],
+    key_groups: &[KeyGroupResult],
+) -> Vec<(&'a Path, u64, Digest)> {
+    let mut shared: HashMap<&Path, Digest> = HashMap::new();
+    for kgr in key_groups {
+        for hg in &kgr.hash_groups {
+            if hg.files.len() > 1 {
+                let digest = group_digest(kgr, hg);
+                for file in &hg.files {
+                    shared.insert(&file.path, digest);
+                }
+            }
+        }
+    }
+    let mut files = Vec::new();
+    for dent in by_key.values().flatten().chain(culled) {
+        let digest = shared
+            .get(dent.path())
+            .copied()
+            .unwrap_or_else(|| unique_digest(dent.path()));
+        files.push((dent.path(), dent.size, digest));
+        for link in &dent.links {
+            files.push((link.as_path(), dent.size, digest));
+        }
+    }
+    files
+}
+
+fn is_in_tree(options: &Options, dir: &Path) -> bool {
+    !dir.as_os_str().is_empty() && options.directories.iter().any(|root| dir.starts_with(root))
+}
+
+fn is_reference(options: &Options, dir: &Path) -> bool {
+    options.is_reference_root(options.root_index(dir))
+}
+
+/// Build the tree of directories under the roots, computing each one's digest.
+fn build_tree<'a>(
+    options: &Options,
+    files: &[(&'a Path, u64, Digest)],
+) -> HashMap<&'a Path, DirNode<'a>> {
+    let mut nodes: HashMap<&Path, DirNode> = HashMap::new();
+    for &(path, size, digest) in files {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        if !is_in_tree(options, parent) {
+            continue;
+        }
+        let node = nodes.entry(parent).or_default();
+        node.children
+            .push((path_to_bytes(name.as_ref()), false, digest));
+        for dir in parent
+            .ancestors()
+            .take_while(|dir| is_in_tree(options, dir))
+        {
+            let node = nodes.entry(dir).or_default();
+            node.size += size;
+            node.n_files += 1;
+        }
+    }
+    // Children before their parents, so each digest is complete when it's used.
+    let mut dirs: Vec<&Path> = nodes.keys().copied().collect();
+    dirs.sort_unstable_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in dirs {
+        let node = nodes.get_mut(dir).unwrap();
+        let digest = dir_digest(&mut node.children);
+        node.digest = digest;
+        let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) else {
+            continue;
+        };
+        if let Some(parent) = nodes.get_mut(parent) {
+            parent
+                .children
+                .push((path_to_bytes(name.as_ref()), true, digest));
+        }
+    }
+    nodes
+}
+
+fn dir_result(options: &Options, nodes: &HashMap<&Path, DirNode>, dir: &Path) -> DirResult {
+    let node = &nodes[dir];
+    DirResult {
+        path: ReportPath::new(dir),
+        size: node.size,
+        n_files: node.n_files,
+        reference: is_reference(options, dir),
+    }
+}
+
+/// Groups of directories with identical trees, leaving out those nested in
+/// a reported group, since their duplication follows from their parents'.
+fn identical_dirs<'a>(options: &Options, nodes: &HashMap<&'a Path, DirNode>) -> Vec<Vec<&'a Path>> {
+    let mut by_digest: HashMap<Digest, Vec<&Path>> = HashMap::new();
+    for (dir, node) in nodes {
+        by_digest.entry(node.digest).or_default().push(dir);
+    }
+    let groups: Vec<Vec<&Path>> = by_digest
+        .into_values()
+        .filter(|dirs| dirs.len() > 1 && dirs.iter().any(|dir| !is_reference(options, dir)))
+        .collect();
+    let group_of: HashMap<&Path, usize> = groups
+        .iter()
+        .enumerate()
+        .flat_map(|(i, dirs)| dirs.iter().map(move |dir| (*dir, i)))
+        .collect();
+    groups
+        .into_iter()
+        .filter(|dirs| {
+            let mut parent_groups = dirs
+                .iter()
+                .map(|dir| dir.parent().and_then(|parent| group_of.get(parent)));
+            let first = parent_groups.next().flatten();
+            first.is_none() || !parent_groups.all(|group| group == first)
+        })
+        .map(|mut dirs| {
+            dirs.sort_unstable();
+            dirs
+        })
+        .collect()
+}
+
+/// Pairs of differing directories sharing at least `options.dir_similarity`
+/// of their bytes, counting files with the same contents at the same
+/// relative path, again leaving out pairs nested in a reported pair.
+fn similar_dirs<'a>(
+    options: &Options,
+    files: &[(&'a Path, u64, Digest)],
+    nodes: &HashMap<&'a Path, DirNode>,
+) -> Vec<(f64, &'a Path, &'a Path)> {
+    let mut by_content: HashMap<Digest, Vec<(&Path, u64)>> = HashMap::new();
+    for &(path, size, digest) in files {
+        by_content.entry(digest).or_default().push((path, size));
+    }
+    let mut shared: HashMap<(&Path, &Path), u64> = HashMap::new();
+    for copies in by_content.values() {
+        if copies.len() < 2 || copies.len() > MAX_SIMILARITY_GROUP {
+            continue;
+        }
+        for (i, &(a, size)) in copies.iter().enumerate() {
+            for &(b, _) in &copies[i + 1..] {
+                if a.file_name() != b.file_name() {
+                    continue;
+                }
+                // Credit each pair of ancestors the file is at the same relative path in.
+                let (mut a, mut b) = (a.parent(), b.parent());
+                while let (Some(dir_a), Some(dir_b)) = (a, b) {
+                    if !nodes.contains_key(dir_a)
+                        || !nodes.contains_key(dir_b)
+                        || dir_a.starts_with(dir_b)
+                        || dir_b.starts_with(dir_a)
+                    {
+                        break;
+                    }
+                    let pair = if dir_a < dir_b {
+                        (dir_a, dir_b)
+                    } else {
+                        (dir_b, dir_a)
+                    };
+                    *shared.entry(pair).or_default() += size;
+                    if dir_a.file_name() != dir_b.file_name() {
+                        break;
+                    }
+                    (a, b) = (dir_a.parent(), dir_b.parent());
+                }
+            }
+        }
+    }
+    let similar: HashMap<(&Path, &Path), f64> = shared
+        .into_iter()
+        .filter_map(|((a, b), n_bytes)| {
+            let (node_a, node_b) = (&nodes[a], &nodes[b]);
+            if node_a.digest == node_b.digest
+                || (is_reference(options, a) && is_reference(options, b))
+            {
+                return None;
+            }
+            let similarity = n_bytes as f64 / node_a.size.max(node_b.size) as f64;
+            Some(((a, b), similarity)).filter(|_| similarity >= options.dir_similarity)
+        })
+        .collect();
+    similar
+        .iter()
+        .filter(|((a, b), _)| match (a.parent(), b.parent()) {
+            (Some(parent_a), Some(parent_b)) => {
+                !similar.contains_key(&(parent_a, parent_b))
+                    && !similar.contains_key(&(parent_b, parent_a))
+                    && nodes
+                        .get(parent_a)
+                        .zip(nodes.get(parent_b))
+                        .is_none_or(|(node_a, node_b)| node_a.digest != node_b.digest)
+            }
+            _ => true,
+        })
+        .map(|(&(a, b), &similarity)| (similarity, a, b))
+        .collect()
+}
+
+/// Find directories whose trees of found files are identical, or (with
+/// `--dir-similarity` below 1) nearly so, from the files' hash groups.
+///
+/// Only the files fdf found count, so directories are compared after
+/// filters like `--min-size` and `--glob` have been applied.
+pub fn find_duplicate_dirs(
+    by_key: &KeyToDentsMap,
+    culled: &[AugDirEntry],
+    key_groups: &[KeyGroupResult],
+    options: &Options,
+) -> Vec<DirGroupResult> {
+    let files = file_digests(by_key, culled, key_groups);
+    let nodes = build_tree(options, &files);
+    let mut results: Vec<DirGroupResult> = identical_dirs(options, &nodes)
+        .into_iter()
+        .map(|dirs| DirGroupResult {
+            identical: true,
+            similarity: 1.0,
+            dirs: dirs
+                .into_iter()
+                .map(|dir| dir_result(options, &nodes, dir))
+                .collect(),
+        })
+        .collect();
+    if options.dir_similarity < 1.0 {
+        results.extend(similar_dirs(options, &files, &nodes).into_iter().map(
+            |(similarity, a, b)| DirGroupResult {
+                identical: false,
+                similarity,
+                dirs: vec![
+                    dir_result(options, &nodes, a),
+                    dir_result(options, &nodes, b),
+                ],
+            },
+        ));
+    }
+    let size = |group: &DirGroupResult| group.dirs.iter().map(|dir| dir.size).max();
+    results.sort_unstable_by(|a, b| {
+        b.identical
+            .cmp(&a.identical)
+            .then_with(|| b.similarity.total_cmp(&a.similarity))
+            .then_with(|| size(b).cmp(&size(a)))
+            .then_with(|| a.dirs[0].path.cmp(&b.dirs[0].path))
+    });
+    results
+}
+
+/// With `--duplicate-dirs instead`, drop the hash groups made up of exactly
+/// the files at one relative path in each directory of an identical group,
+/// as those are reported already.
+pub fn drop_files_in_duplicate_dirs(
+    key_groups: &mut [KeyGroupResult],
+    dir_groups: &[DirGroupResult],
+) {
+    let group_of: HashMap<&Path, &DirGroupResult> = dir_groups
+        .iter()
+        .filter(|group| group.identical)
+        .flat_map(|group| group.dirs.iter().map(move |dir| (&*dir.path, group)))
+        .collect();
+    let is_covered = |hg: &HashGroupResult| {
+        let files: HashSet<&Path> = hg.files.iter().map(|file| &*file.path).collect();
+        let first: &Path = &hg.files[0].path;
+        first.ancestors().skip(1).any(|dir| {
+            let (Some(group), Ok(relative)) = (group_of.get(dir), first.strip_prefix(dir)) else {
+                return false;
+            };
+            group.dirs.len() == files.len()
+                && group
+                    .dirs
+                    .iter()
+                    .all(|dir| files.contains(&*dir.path.join(relative)))
+        })
+    };
+    for kgr in key_groups {
+        kgr.hash_groups
+            .retain(|hg| hg.files.len() < 2 || !is_covered(hg));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::FileResult;
+
+    fn dir_group(identical: bool, dirs: &[&str]) -> DirGroupResult {
+        DirGroupResult {
+            identical,
+            similarity: 1.0,
+            dirs: dirs
+                .iter()
+                .map(|dir| DirResult {
+                    path: ReportPath::new(Path::new(dir)),
+                    size: 8,
+                    n_files: 2,
+                    reference: false,
+                })
+                .collect(),
+        }
+    }
+
+    fn key_group(hash_groups: &[&[&str]]) -> KeyGroupResult {
+        let hash_groups = hash_groups
+            .iter()
+            .map(|paths| {
+                paths
+                    .iter()
+                    .map(|path| FileResult::for_test(Path::new(path), None))
+                    .collect()
+            })
+            .collect();
+        KeyGroupResult::for_test(4, hash_groups)
+    }
+
+    fn remaining(kgr: &KeyGroupResult) -> Vec<String> {
+        kgr.hash_groups
+            .iter()
+            .map(|hg| hg.files[0].path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn drops_groups_matching_identical_dirs() {
+        let mut key_groups = [key_group(&[
+            &["/a/x.txt", "/b/x.txt"],
+            &["/a/sub/y.txt", "/b/sub/y.txt"],
+        ])];
+        drop_files_in_duplicate_dirs(&mut key_groups, &[dir_group(true, &["/a", "/b"])]);
+        assert!(key_groups[0].hash_groups.is_empty());
+    }
+
+    #[test]
+    fn keeps_groups_of_merely_similar_dirs() {
+        let mut key_groups = [key_group(&[&["/a/x.txt", "/b/x.txt"]])];
+        drop_files_in_duplicate_dirs(&mut key_groups, &[dir_group(false, &["/a", "/b"])]);
+        assert_eq!(remaining(&key_groups[0]), ["/a/x.txt"]);
+    }
+
+    #[test]
+    fn keeps_groups_reaching_beyond_the_dirs() {
+        let mut key_groups = [key_group(&[
+            // Another copy outside the identical directories.
+            &["/a/x.txt", "/b/x.txt", "/c/x.txt"],
+            // Copies at different relative paths within them.
+            &["/a/y.txt", "/a/z.txt", "/b/y.txt", "/b/z.txt"],
+        ])];
+        drop_files_in_duplicate_dirs(&mut key_groups, &[dir_group(true, &["/a", "/b"])]);
+        assert_eq!(remaining(&key_groups[0]), ["/a/x.txt", "/a/y.txt"]);
+    }
+}
@@ -1,22 +1,72 @@
-use super::options::{NameGroupingOption, Options};
+use super::interrupt::check_and_reset_interrupt;
+use super::options::{
+    DuplicateDirsOption, NameGroupingOption, Options, ReportOption, SymlinkOption,
+};
 use super::output::{FindStats, HashStats};
-use crate::fdf::interrupt::{check_and_reset_interrupt, is_interrupted};
-use humansize::{format_size, DECIMAL};
+use super::walk::{read_file_list, walk, Found, WalkResult};
 use indicatif::ProgressBar;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use string_cache::DefaultAtom as Atom;
-use walkdir::{DirEntry, WalkDir};
+
+/// Device and inode number of a file, on platforms that have them.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct FileId {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl FileId {
+    #[cfg(unix)]
+    pub fn from_metadata(meta: &Metadata) -> Option<FileId> {
+        use std::os::unix::fs::MetadataExt;
+        Some(FileId {
+            dev: meta.dev(),
+            ino: meta.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_metadata(_meta: &Metadata) -> Option<FileId> {
+        None
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct AugDirEntry {
-    pub dir_entry: DirEntry,
+    pub path: PathBuf,
     pub size: u64,
+    pub mtime: Option<SystemTime>,
+    pub id: Option<FileId>,
+    /// Other paths found for the same file.
+    pub links: Vec<PathBuf>,
+    /// Symlinks pointing at this file, with `--symlinks report`.
+    pub symlinks: Vec<PathBuf>,
+    /// Index of the `--directory` root this entry was found under.
+    pub root: usize,
+    /// Whether the file is only reached through reported `symlinks` to it
+    /// from outside of what was found, so is never acted on.
+    pub external: bool,
 }
 
 impl AugDirEntry {
     pub fn path(&self) -> &Path {
-        self.dir_entry.path()
+        &self.path
+    }
+
+    /// Id of the device the file lives on, if known.
+    pub fn dev(&self) -> Option<u64> {
+        self.id.map(|id| id.dev)
+    }
+
+    /// Whether the file must not be acted on, for being under a `--reference`
+    /// root or only reached through symlinks.
+    pub fn is_reference(&self, options: &Options) -> bool {
+        self.external || options.is_reference_root(self.root)
     }
 }
 
@@ -33,16 +83,16 @@ fn group_key(options: &Options, dent: &AugDirEntry) -> GroupKey {
     let extension = match (ng, ex) {
         (NameGroupingOption::IgnoreName, _) => Atom::from("<none>"),
         (NameGroupingOption::FullNameWhenNoExtension, None) => {
-            Atom::from(dent.path().file_name().unwrap().to_str().unwrap())
+            Atom::from(dent.path().file_name().unwrap().to_string_lossy())
         }
         (NameGroupingOption::SingleGroupWhenNoExtension, None) => Atom::from("<none>"),
-        (_, Some(ps)) => Atom::from(ps.to_str().unwrap().to_lowercase()),
+        (_, Some(ps)) => Atom::from(ps.to_string_lossy().to_lowercase()),
     };
     GroupKey { size, extension }
 }
 
-type StringToDentMap = HashMap<String, AugDirEntry>;
-pub type KeyToStringToDentMap = HashMap<GroupKey, StringToDentMap>;
+type PathToDentMap = HashMap<PathBuf, AugDirEntry>;
+pub type KeyToPathToDentMap = HashMap<GroupKey, PathToDentMap>;
 pub type KeyToDentsMap = HashMap<GroupKey, Vec<AugDirEntry>>;
 
 fn calculate_hash_stats(by_key: &KeyToDentsMap) -> HashStats {
@@ -59,100 +109,273 @@ fn calculate_hash_stats(by_key: &KeyToDentsMap) -> HashStats {
         n_files,
         n_bytes,
         n_groups: by_key.len() as u64,
+        n_cache_hits: 0,
+        n_cache_misses: 0,
+    }
+}
+
+pub struct FindResult {
+    pub find_stats: FindStats,
+    pub hash_stats: HashStats,
+    pub by_key: KeyToDentsMap,
+    pub precull_files: Option<KeyToPathToDentMap>,
+    /// Files with more than one path pointing at them; only the first path
+    /// found is hashed, with the others recorded in `links`.
+    pub hardlinked: Vec<AugDirEntry>,
+    pub dangling_symlinks: Vec<PathBuf>,
+    /// Files culled without hashing, for being alone in their key group or
+    /// only found under `--reference` roots; only collected for
+    /// `--output-unique` and `--duplicate-dirs`.
+    pub culled: Vec<AugDirEntry>,
+}
+
+/// Record a dangling symlink once, by its shortest path, even if it's reached through several.
+fn add_dangling(dangling: &mut HashMap<FileId, PathBuf>, path: PathBuf) {
+    if let Some(id) = path
+        .symlink_metadata()
+        .ok()
+        .and_then(|meta| FileId::from_metadata(&meta))
+    {
+        let shortest = dangling.entry(id).or_insert_with(|| path.clone());
+        if path.components().count() < shortest.components().count() {
+            *shortest = path;
+        }
+    }
+}
+
+/// Canonical paths of directories, cached as most share them with many files.
+#[derive(Default)]
+struct CanonicalDirs(HashMap<PathBuf, Option<PathBuf>>);
+
+impl CanonicalDirs {
+    fn get(&mut self, dir: &Path) -> Option<&Path> {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        self.0
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| fs::canonicalize(dir).ok())
+            .as_deref()
+    }
+}
+
+/// Whether `path` goes through a followed symlink somewhere below `root`.
+fn is_via_symlink(path: &Path, root: &Path, dirs: &mut CanonicalDirs) -> bool {
+    let Some(parent) = path.parent() else {
+        return true;
+    };
+    if !path
+        .symlink_metadata()
+        .is_ok_and(|meta| !meta.file_type().is_symlink())
+    {
+        return true;
+    }
+    let expected = if root.as_os_str().is_empty() {
+        // Listed with `--files-from`, so there is no root; check the whole path.
+        std::path::absolute(parent).ok()
+    } else {
+        let relative = parent.strip_prefix(root).unwrap_or(parent);
+        dirs.get(root).map(|root| root.join(relative))
+    };
+    match (expected, dirs.get(parent)) {
+        (Some(expected), Some(parent)) => expected != parent,
+        _ => true,
     }
 }
 
-pub fn find_files(
-    options: &Options,
-    return_precull: bool,
-) -> (
-    FindStats,
-    HashStats,
-    KeyToDentsMap,
-    Option<KeyToStringToDentMap>,
-) {
+/// With `--symlinks report`, the target of symlinks to a file that wasn't
+/// found (for being outside the roots or filtered out), so duplicates of it
+/// can be reported along with the symlinks.
+fn external_target(options: &Options, link: &Path) -> Option<AugDirEntry> {
+    let path = fs::canonicalize(link).ok()?;
+    let metadata = fs::metadata(&path).ok()?;
+    Some(AugDirEntry {
+        size: metadata.len(),
+        mtime: metadata.modified().ok(),
+        id: FileId::from_metadata(&metadata),
+        links: Vec::new(),
+        symlinks: Vec::new(),
+        root: options.root_index(link),
+        external: true,
+        path,
+    })
+}
+
+pub fn find_files(options: &Options, return_precull: bool) -> anyhow::Result<FindResult> {
     let prog = ProgressBar::new_spinner();
-    let mut n_dirs: u64 = 0;
+    let WalkResult { mut found, n_dirs } = match &options.files_from {
+        Some(source) => read_file_list(options, source, &prog)?,
+        None => walk(options, &prog),
+    };
+    prog.set_message("Merging results...");
+    // Walker threads finish in no particular order; sort so results don't depend on it.
+    found.sort_unstable_by(|a, b| a.sort_key().cmp(&b.sort_key()));
     let mut n_files: u64 = 0;
     let mut n_bytes: u64 = 0;
-    let by_key_and_path: KeyToStringToDentMap = options
-        .directories
-        .iter()
-        .map(|dir| {
-            let mut by_key_and_path: KeyToStringToDentMap = HashMap::new();
-            let walker = WalkDir::new(dir).into_iter();
-            for er in walker.filter_entry(|entry| options.is_entry_included(entry)) {
-                if is_interrupted() {
-                    break;
-                }
-                let entry = match er {
-                    Ok(entry) => entry,
-                    Err(err) => {
-                        eprintln!("[!] {}", err);
-                        continue;
+    let mut n_hardlinks: u64 = 0;
+    let mut n_symlinks: u64 = 0;
+    let mut dangling_symlinks: HashMap<FileId, PathBuf> = HashMap::new();
+    // Symlinks seen with `--symlinks report`, to be matched up with their targets.
+    let mut symlinks: Vec<(FileId, PathBuf)> = Vec::new();
+    let follow = options.symlinks == SymlinkOption::Follow;
+    let mut canonical_dirs = CanonicalDirs::default();
+    let mut by_key_and_path: KeyToPathToDentMap = HashMap::new();
+    // The first path found for each file.
+    let mut first_seen: HashMap<FileId, (GroupKey, PathBuf)> = HashMap::new();
+    for item in found {
+        let (root, path, size, mtime, id) = match item {
+            Found::File {
+                root,
+                path,
+                size,
+                mtime,
+                id,
+            } => (root, path, size, mtime, id),
+            Found::Symlink { path, target } => {
+                symlinks.push((target, path));
+                continue;
+            }
+            Found::Dangling(path) => {
+                add_dangling(&mut dangling_symlinks, path);
+                continue;
+            }
+        };
+        if let Some((key, first_path)) = id.and_then(|id| first_seen.get_mut(&id)) {
+            if *first_path == path {
+                // Found again under an overlapping root.
+                continue;
+            }
+            let by_path = by_key_and_path.get_mut(key).unwrap();
+            let first_root = &options.directories[by_path[first_path].root];
+            let first_via_symlink =
+                follow && is_via_symlink(first_path, first_root, &mut canonical_dirs);
+            let via_symlink =
+                follow && is_via_symlink(&path, &options.directories[root], &mut canonical_dirs);
+            if via_symlink || first_via_symlink {
+                // The same file reached again through a followed symlink; only
+                // hash it once, preferring a path without symlinks in it.
+                n_symlinks += 1;
+                if first_via_symlink && !via_symlink {
+                    let mut first = by_path.remove(first_path).unwrap();
+                    first.path = path.clone();
+                    first.root = root;
+                    let new_key = group_key(options, &first);
+                    if by_path.is_empty() {
+                        by_key_and_path.remove(key);
                     }
-                };
-                if entry.file_type().is_dir() {
-                    n_dirs += 1;
-                    continue;
+                    by_key_and_path
+                        .entry(new_key.clone())
+                        .or_default()
+                        .insert(path.clone(), first);
+                    *key = new_key;
+                    *first_path = path;
                 }
-                // TODO: Process symlinks gracefully
-                if entry.file_type().is_symlink() {
-                    continue;
+            } else {
+                let first = by_path.get_mut(first_path).unwrap();
+                if !first.links.contains(&path) {
+                    first.links.push(path);
+                    n_hardlinks += 1;
                 }
-                let size = entry.metadata().unwrap().len();
-                if size == 0 || size < options.min_size || size > options.max_size {
+            }
+            continue;
+        }
+        n_files += 1;
+        n_bytes += size;
+        if options.verbosity >= 3 {
+            println!("{}", path.display());
+        }
+        let aug_entry = AugDirEntry {
+            path: path.clone(),
+            size,
+            mtime,
+            id,
+            links: Vec::new(),
+            symlinks: Vec::new(),
+            root,
+            external: false,
+        };
+        let key = group_key(options, &aug_entry);
+        if let Some(id) = id {
+            first_seen.insert(id, (key.clone(), path.clone()));
+        }
+        by_key_and_path
+            .entry(key)
+            .or_default()
+            .insert(path, aug_entry);
+    }
+    for (id, link) in symlinks {
+        let (key, first_path) = match first_seen.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let Some(target) = external_target(options, &link) else {
                     continue;
-                }
-                n_files += 1;
-                n_bytes += size;
-                if options.verbosity >= 3 {
-                    println!("{}", entry.path().display());
-                }
-                let path_str = entry.path().to_str().unwrap().to_string();
-                let aug_entry = AugDirEntry {
-                    dir_entry: entry,
-                    size,
                 };
-                let key = group_key(options, &aug_entry);
-                let by_path = by_key_and_path.entry(key).or_insert_with(HashMap::new);
-                by_path.insert(path_str, aug_entry);
-                let size = format_size(n_bytes, DECIMAL);
-                prog.set_message(format!("{} dirs, {} files, {}...", n_dirs, n_files, size));
-                prog.inc(1);
+                let key = group_key(options, &target);
+                let path = target.path.clone();
+                by_key_and_path
+                    .entry(key.clone())
+                    .or_default()
+                    .insert(path.clone(), target);
+                entry.insert((key, path))
             }
-            by_key_and_path
-        })
-        .fold(HashMap::new(), |mut accmap, map| {
-            for (key, ents) in map {
-                accmap.entry(key).or_insert_with(HashMap::new).extend(ents);
-            }
-            accmap
-        });
+        };
+        let target = by_key_and_path
+            .get_mut(key)
+            .and_then(|by_path| by_path.get_mut(first_path))
+            .unwrap();
+        target.symlinks.push(link);
+        n_symlinks += 1;
+    }
     let mut by_key: KeyToDentsMap = HashMap::new();
+    let mut culled: Vec<AugDirEntry> = Vec::new();
+    let keep_culled = options.report_unique != ReportOption::None
+        || options.duplicate_dirs != DuplicateDirsOption::Off;
     let find_stats = FindStats {
         interrupted: check_and_reset_interrupt(),
         n_bytes,
         n_dirs,
         n_files,
+        n_hardlinks,
+        n_symlinks,
         n_precull_groups: by_key_and_path.len() as u64,
     };
     for (key, ent_map) in &by_key_and_path {
-        if ent_map.len() > 1 {
-            by_key.insert(key.clone(), ent_map.values().cloned().collect());
+        // Files only under `--reference` roots (or only reached through
+        // symlinks) aren't worth hashing on their own.
+        if ent_map.len() > 1 && ent_map.values().any(|dent| !dent.is_reference(options)) {
+            let mut dents: Vec<AugDirEntry> = ent_map.values().cloned().collect();
+            dents.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+            by_key.insert(key.clone(), dents);
+        } else if keep_culled {
+            culled.extend(ent_map.values().filter(|dent| !dent.external).cloned());
         }
     }
+    let hardlinked = by_key_and_path
+        .values()
+        .flat_map(|ent_map| ent_map.values())
+        .filter(|dent| !dent.links.is_empty())
+        .cloned()
+        .collect();
     prog.set_message("Calculating statistics...");
     let hash_stats = calculate_hash_stats(&by_key);
     prog.finish_and_clear();
-    (
+    Ok(FindResult {
         find_stats,
         hash_stats,
         by_key,
-        if return_precull {
+        precull_files: if return_precull {
             Some(by_key_and_path)
         } else {
             None
         },
-    )
+        hardlinked,
+        culled,
+        dangling_symlinks: {
+            let mut paths: Vec<PathBuf> = dangling_symlinks.into_values().collect();
+            paths.sort();
+            paths
+        },
+    })
 }
@@ -0,0 +1,72 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::{Path, PathBuf};
+
+/// `--glob` and `--exclude-glob` patterns, matched relative to one `--directory` root.
+///
+/// Patterns have gitignore semantics: later patterns override earlier ones,
+/// and a leading `!` negates a pattern.
+#[derive(Debug)]
+pub struct GlobFilter {
+    root: PathBuf,
+    include: Gitignore,
+    exclude: Gitignore,
+}
+
+fn build_matcher(patterns: &[String]) -> Result<Gitignore, ignore::Error> {
+    // Paths are made relative to the root before matching, so the matcher itself has none.
+    let mut builder = GitignoreBuilder::new("");
+    for pattern in patterns {
+        builder.add_line(None, pattern)?;
+    }
+    builder.build()
+}
+
+impl GlobFilter {
+    pub fn new(
+        root: &Path,
+        include: &[String],
+        exclude: &[String],
+    ) -> Result<GlobFilter, ignore::Error> {
+        Ok(GlobFilter {
+            root: root.to_path_buf(),
+            include: build_matcher(include)?,
+            exclude: build_matcher(exclude)?,
+        })
+    }
+
+    /// The part of `path` below the root, or `None` for the root itself or paths outside it.
+    ///
+    /// Paths given with `--files-from` have an empty root; absolute ones are
+    /// matched relative to the filesystem root.
+    fn relative<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let relative = relative.strip_prefix("/").unwrap_or(relative);
+        Some(relative).filter(|relative| !relative.as_os_str().is_empty())
+    }
+
+    pub fn is_file_included(&self, path: &Path) -> bool {
+        let Some(relative) = self.relative(path) else {
+            return true;
+        };
+        if self
+            .exclude
+            .matched_path_or_any_parents(relative, false)
+            .is_ignore()
+        {
+            return false;
+        }
+        self.include.is_empty()
+            || self
+                .include
+                .matched_path_or_any_parents(relative, false)
+                .is_ignore()
+    }
+
+    /// Directories are only pruned by `--exclude-glob`; `--glob` applies to the files in them.
+    pub fn is_dir_included(&self, path: &Path) -> bool {
+        match self.relative(path) {
+            Some(relative) => !self.exclude.matched(relative, true).is_ignore(),
+            None => true,
+        }
+    }
+}
@@ -1,78 +1,149 @@
+use super::cache::{CacheKey, HashCache};
 use super::find::{AugDirEntry, GroupKey};
-use super::options::{HashAlgorithm, Options};
+use super::hasher::HashAlgorithm;
+use super::options::{IoMode, Options};
+use super::read::hash_file_range;
 use rayon::prelude::*;
-use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::error::Error;
-use std::fs::File;
-use std::hash::Hasher;
-use std::io;
-use std::io::{copy, BufReader, Read, Write};
-use twox_hash::XxHash64;
+use std::path::Path;
 
-// via https://stackoverflow.com/questions/48533445/proper-way-to-hash-a-reader-in-rust
-struct HashWriter<T: Hasher>(T);
-impl<T: Hasher> Write for HashWriter<T> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf);
-        Ok(buf.len())
+/// A single pass over (part of) the files in a key group.
+///
+/// The cheap prefix and suffix stages are only used to split groups up;
+/// files that still collide after them are hashed in full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HashStage {
+    Prefix,
+    Suffix,
+    Full,
+}
+
+impl HashStage {
+    /// Get the (offset, length) of the range hashed by this stage for a file
+    /// of which `len` bytes are to be considered.
+    fn range(&self, len: u64, options: &Options) -> (u64, u64) {
+        match self {
+            HashStage::Prefix => (0, options.prefix_bytes),
+            HashStage::Suffix => (len - options.suffix_bytes, options.suffix_bytes),
+            HashStage::Full => (0, len),
+        }
+    }
+
+    fn label(&self, hash: String) -> String {
+        match self {
+            HashStage::Prefix => format!("prefix-{}", hash),
+            HashStage::Suffix => format!("suffix-{}", hash),
+            HashStage::Full => hash,
+        }
     }
+}
 
-    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.write(buf).map(|_| ())
+fn hash_stages(len: u64, options: &Options) -> Vec<HashStage> {
+    let mut stages = Vec::new();
+    if options.prefix_bytes > 0 && len > options.prefix_bytes {
+        stages.push(HashStage::Prefix);
+    }
+    if options.suffix_bytes > 0 && len > options.prefix_bytes + options.suffix_bytes {
+        stages.push(HashStage::Suffix);
     }
+    stages.push(HashStage::Full);
+    stages
+}
+
+/// Hash `len` bytes of a file from `offset`, returning the digest as `algorithm-hex`.
+fn hash_range(
+    path: &Path,
+    algorithm: &HashAlgorithm,
+    key: Option<&[u8; 32]>,
+    offset: u64,
+    len: u64,
+    io_mode: IoMode,
+) -> Result<String, Box<dyn Error>> {
+    let mut hasher = algorithm.hasher(key)?;
+    hash_file_range(path, &mut *hasher, offset, len, io_mode)?;
+    Ok(format!(
+        "{}-{}",
+        algorithm.name,
+        hex::encode(hasher.finalize())
+    ))
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+/// Identifies the algorithm (and key, if any) digests were computed with in the hash cache.
+fn cache_algorithm(options: &Options) -> String {
+    match &options.hash_key {
+        Some(key) if options.hash_algorithm.keyed => {
+            // Keep the key itself out of the cache file.
+            let fingerprint = blake3::hash(key);
+            format!(
+                "{}-{}",
+                options.hash_algorithm.name,
+                &fingerprint.to_hex()[..16]
+            )
+        }
+        _ => options.hash_algorithm.name.to_string(),
     }
 }
 
+/// Compute the full digest of a file of the given size, as reported for hash groups.
+pub fn hash_path(
+    path: &Path,
+    size: u64,
+    algorithm: &HashAlgorithm,
+    key: Option<&[u8; 32]>,
+    hash_bytes: u64,
+    io_mode: IoMode,
+) -> Result<String, Box<dyn Error>> {
+    hash_range(path, algorithm, key, 0, size.min(hash_bytes), io_mode)
+}
+
 fn hash_file<'a>(
     key: &'a GroupKey,
     dent: &'a AugDirEntry,
     options: &Options,
+    cache: Option<&HashCache>,
+    stage: HashStage,
 ) -> Result<(&'a AugDirEntry, String), Box<dyn Error>> {
-    let f = File::open(dent.dir_entry.path())?.take(options.hash_bytes);
-    let buf_cap = options.hash_bytes.min(524_288).max(8_192) as usize;
-    let mut reader = BufReader::with_capacity(buf_cap, f);
-    let hash: String = match options.hash_algorithm {
-        HashAlgorithm::Blake3 => {
-            let mut b3 = blake3::Hasher::new();
-            let n = copy(&mut reader, &mut b3)?;
-            assert!(n <= options.hash_bytes);
-            format!("blake3-{}", hex::encode(b3.finalize().as_bytes()))
-        }
-        HashAlgorithm::Sha256 => {
-            let mut sha256 = Sha256::new();
-            let n = copy(&mut reader, &mut sha256)?;
-            assert!(n <= options.hash_bytes);
-            format!("sha256-{}", hex::encode(sha256.finalize()))
-        }
-        HashAlgorithm::Xxh64 => {
-            let seed: u64 = key.size % (std::u32::MAX as u64);
-            let hasher = XxHash64::with_seed(seed);
-            let mut hw = HashWriter(hasher);
-            let n = copy(&mut reader, &mut hw)?;
-            assert!(n <= options.hash_bytes);
-            let hash_u = hw.0.finish();
-            format!("xxh64-{:x}-{:x}", key.size, hash_u)
+    let (offset, len) = stage.range(key.size.min(options.hash_bytes), options);
+    let cache_key = cache.and_then(|_| CacheKey::new(dent, cache_algorithm(options), offset, len));
+    let cached = match (cache, &cache_key) {
+        (Some(cache), Some(cache_key)) => cache.get(cache_key),
+        _ => None,
+    };
+    let hash = match cached {
+        Some(hash) => hash,
+        None => {
+            let hash = hash_range(
+                dent.path(),
+                &options.hash_algorithm,
+                options.hash_key.as_ref(),
+                offset,
+                len,
+                options.io_mode,
+            )?;
+            if let (Some(cache), Some(cache_key)) = (cache, cache_key) {
+                cache.insert(cache_key, dent.path(), &hash);
+            }
+            hash
         }
     };
-
+    let hash = stage.label(hash);
     if options.verbosity >= 2 {
         println!("{} {}", dent.path().display(), hash);
     }
     Ok((dent, hash))
 }
 
-pub fn hash_key_group<'a>(
+fn hash_stage_group<'a>(
     key: &'a GroupKey,
-    dents: &'a [AugDirEntry],
+    dents: &[&'a AugDirEntry],
     options: &Options,
+    cache: Option<&HashCache>,
+    stage: HashStage,
 ) -> HashMap<String, Vec<&'a AugDirEntry>> {
     let hashes: Vec<Result<(&AugDirEntry, String), ()>> = dents
         .par_iter()
-        .map(|dent| match hash_file(key, dent, options) {
+        .map(|dent| match hash_file(key, dent, options, cache, stage) {
             Ok(v) => Ok(v),
             Err(x) => {
                 println!("Unable to hash {:?}: {}", dent, x);
@@ -82,7 +153,51 @@ pub fn hash_key_group<'a>(
         .collect();
     let mut hm: HashMap<String, Vec<&AugDirEntry>> = HashMap::new();
     for (dent, hash) in hashes.into_iter().flatten() {
-        hm.entry(hash).or_insert_with(Vec::new).push(dent)
+        hm.entry(hash).or_default().push(dent);
     }
     hm
 }
+
+/// The files of a key group, grouped by digest.
+pub struct KeyGroupHashes<'a> {
+    /// Files grouped by the digest of their full contents (up to `--hash-bytes`).
+    pub groups: Vec<(String, Vec<&'a AugDirEntry>)>,
+    /// Files told apart from all others by a prefix or suffix hash, which
+    /// therefore have no full digest.
+    pub distinct: Vec<&'a AugDirEntry>,
+}
+
+/// Hash the files in a key group, returning them grouped by digest.
+///
+/// Files are first bucketed by cheap prefix and suffix hashes; only those
+/// still colliding are hashed in full.
+///
+/// If a cache is given, digests are looked up from and stored into it.
+pub fn hash_key_group<'a>(
+    key: &'a GroupKey,
+    dents: &'a [AugDirEntry],
+    options: &Options,
+    cache: Option<&HashCache>,
+) -> KeyGroupHashes<'a> {
+    let mut groups: Vec<(String, Vec<&AugDirEntry>)> = Vec::new();
+    let mut distinct: Vec<&AugDirEntry> = Vec::new();
+    let mut candidates: Vec<Vec<&AugDirEntry>> = vec![dents.iter().collect()];
+    for stage in hash_stages(key.size.min(options.hash_bytes), options) {
+        let mut next_candidates = Vec::new();
+        for group in candidates {
+            for (hash, group) in hash_stage_group(key, &group, options, cache, stage) {
+                if stage == HashStage::Full {
+                    groups.push((hash, group));
+                } else if group.len() <= 1 {
+                    distinct.extend(group);
+                } else {
+                    next_candidates.push(group);
+                }
+            }
+        }
+        candidates = next_candidates;
+    }
+    groups.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    distinct.sort_unstable_by(|a, b| a.path().cmp(b.path()));
+    KeyGroupHashes { groups, distinct }
+}
@@ -0,0 +1,208 @@
+use lazy_static::lazy_static;
+use md5::Md5;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::hash::Hasher;
+use std::sync::RwLock;
+use twox_hash::XxHash64;
+use xxhash_rust::xxh3::Xxh3;
+
+/// A streaming hash over (part of) a file's contents.
+pub trait FileHasher: Send {
+    fn update(&mut self, data: &[u8]);
+
+    /// Like `update`, for a large chunk of data that may be hashed on several threads.
+    fn update_parallel(&mut self, data: &[u8]) {
+        self.update(data);
+    }
+
+    /// The digest of everything passed to `update`, in the byte order the
+    /// usual tools print it (e.g. `md5sum`, `xxhsum`).
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+/// A named hash algorithm, as chosen with `--hash-algorithm`.
+///
+/// Algorithms are looked up by name in a registry, to which library users
+/// can add their own with [`register`] before parsing arguments.
+#[derive(Clone, Copy)]
+pub struct HashAlgorithm {
+    /// Name of the algorithm, also used to prefix its digests.
+    pub name: &'static str,
+    /// Whether the algorithm needs a `--hash-key`.
+    pub keyed: bool,
+    /// Create a hasher; keyed algorithms are always given a key.
+    pub new: fn(key: Option<&[u8; 32]>) -> Box<dyn FileHasher>,
+}
+
+impl HashAlgorithm {
+    pub fn hasher(&self, key: Option<&[u8; 32]>) -> Result<Box<dyn FileHasher>, String> {
+        if self.keyed && key.is_none() {
+            return Err(format!("{} requires a hash key", self.name));
+        }
+        Ok((self.new)(key))
+    }
+}
+
+impl PartialEq for HashAlgorithm {
+    fn eq(&self, other: &HashAlgorithm) -> bool {
+        self.name == other.name
+    }
+}
+
+impl fmt::Debug for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl Serialize for HashAlgorithm {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name)
+    }
+}
+
+impl<'de> Deserialize<'de> for HashAlgorithm {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HashAlgorithm, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        lookup(&name).ok_or_else(|| D::Error::custom(format!("unknown hash algorithm {}", name)))
+    }
+}
+
+struct Blake3Hasher(blake3::Hasher);
+
+impl FileHasher for Blake3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn update_parallel(&mut self, data: &[u8]) {
+        self.0.update_rayon(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().as_bytes().to_vec()
+    }
+}
+
+/// Any hash implementing the RustCrypto `Digest` trait.
+struct DigestHasher<D: Digest + Send>(D);
+
+impl<D: Digest + Send> FileHasher for DigestHasher<D> {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.0, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().to_vec()
+    }
+}
+
+struct Xxh64Hasher(XxHash64);
+
+impl FileHasher for Xxh64Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.write(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finish().to_be_bytes().to_vec()
+    }
+}
+
+struct Xxh3Hasher(Box<Xxh3>);
+
+impl FileHasher for Xxh3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.digest128().to_be_bytes().to_vec()
+    }
+}
+
+struct Crc32cHasher(u32);
+
+impl FileHasher for Crc32cHasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0 = crc32c::crc32c_append(self.0, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+}
+
+fn builtin_algorithms() -> Vec<HashAlgorithm> {
+    vec![
+        HashAlgorithm {
+            name: "blake3",
+            keyed: false,
+            new: |_| Box::new(Blake3Hasher(blake3::Hasher::new())),
+        },
+        HashAlgorithm {
+            name: "blake3-keyed",
+            keyed: true,
+            new: |key| Box::new(Blake3Hasher(blake3::Hasher::new_keyed(key.unwrap()))),
+        },
+        HashAlgorithm {
+            name: "sha256",
+            keyed: false,
+            new: |_| Box::new(DigestHasher(Sha256::new())),
+        },
+        HashAlgorithm {
+            name: "sha1",
+            keyed: false,
+            new: |_| Box::new(DigestHasher(Sha1::new())),
+        },
+        HashAlgorithm {
+            name: "md5",
+            keyed: false,
+            new: |_| Box::new(DigestHasher(Md5::new())),
+        },
+        HashAlgorithm {
+            name: "xxh64",
+            keyed: false,
+            new: |_| Box::new(Xxh64Hasher(XxHash64::with_seed(0))),
+        },
+        HashAlgorithm {
+            name: "xxh3-128",
+            keyed: false,
+            new: |_| Box::new(Xxh3Hasher(Box::new(Xxh3::new()))),
+        },
+        HashAlgorithm {
+            name: "crc32c",
+            keyed: false,
+            new: |_| Box::new(Crc32cHasher(0)),
+        },
+    ]
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<Vec<HashAlgorithm>> = RwLock::new(builtin_algorithms());
+}
+
+/// Make an algorithm available by name, replacing any registered under the same name.
+pub fn register(algorithm: HashAlgorithm) {
+    let mut registry = REGISTRY.write().unwrap();
+    registry.retain(|existing| existing.name != algorithm.name);
+    registry.push(algorithm);
+}
+
+pub fn lookup(name: &str) -> Option<HashAlgorithm> {
+    let registry = REGISTRY.read().unwrap();
+    registry
+        .iter()
+        .find(|algorithm| algorithm.name == name)
+        .copied()
+}
+
+/// Names of all registered algorithms, in registration order.
+pub fn names() -> Vec<&'static str> {
+    let registry = REGISTRY.read().unwrap();
+    registry.iter().map(|algorithm| algorithm.name).collect()
+}
@@ -0,0 +1,68 @@
+use super::report_path::ReportPath;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::time::SystemTime;
+
+const JOURNAL_VERSION: u32 = 2;
+
+/// A file acted upon, and the file it duplicated.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub path: ReportPath,
+    pub size: u64,
+    pub mtime_ns: Option<i64>,
+    pub digest: String,
+    pub keeper: ReportPath,
+    /// The kept file's mtime when scanned, to tell whether it's still the same.
+    pub keeper_mtime_ns: Option<i64>,
+    /// Where the file was moved to, for moves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<ReportPath>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Journal {
+    pub version: u32,
+    pub action: String,
+    pub entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    pub fn new(action: &str, entries: Vec<JournalEntry>) -> Journal {
+        Journal {
+            version: JOURNAL_VERSION,
+            action: action.to_string(),
+            entries,
+        }
+    }
+}
+
+/// Name for a journal when none is given, unique to the second it's made.
+pub fn default_journal_name() -> String {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    format!("fdf-journal-{}.json", secs)
+}
+
+/// Write the journal and make sure it's on disk before returning.
+///
+/// Fails if the file exists, as it may be the only record of an earlier run.
+pub fn write_journal(path: &Path, journal: &Journal) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, journal)?;
+    writer.into_inner()?.sync_all()
+}
+
+pub fn read_journal(path: &Path) -> anyhow::Result<Journal> {
+    let reader = BufReader::new(File::open(path)?);
+    let journal: Journal = serde_json::from_reader(reader)?;
+    if journal.version != JOURNAL_VERSION {
+        anyhow::bail!("unsupported journal version {}", journal.version);
+    }
+    Ok(journal)
+}
@@ -0,0 +1,60 @@
+use super::find::AugDirEntry;
+use super::options::{KeepRule, Options};
+use std::cmp::Ordering;
+use std::path::Path;
+
+/// Index of the first preferred directory the path is under;
+/// paths not under any of them rank last.
+fn keep_under_rank(path: &Path, options: &Options) -> usize {
+    options
+        .keep_under
+        .iter()
+        .position(|prefix| path.starts_with(prefix))
+        .unwrap_or(options.keep_under.len())
+}
+
+fn compare_by_rule(rule: &KeepRule, a: &AugDirEntry, b: &AugDirEntry) -> Ordering {
+    match rule {
+        // Files without a known mtime are never preferred.
+        KeepRule::Oldest => match (a.mtime, b.mtime) {
+            (Some(ma), Some(mb)) => ma.cmp(&mb),
+            (ma, mb) => ma.is_none().cmp(&mb.is_none()),
+        },
+        KeepRule::Newest => match (a.mtime, b.mtime) {
+            (Some(ma), Some(mb)) => mb.cmp(&ma),
+            (ma, mb) => ma.is_none().cmp(&mb.is_none()),
+        },
+        KeepRule::ShortestPath => a.path().as_os_str().len().cmp(&b.path().as_os_str().len()),
+        KeepRule::LongestPath => b.path().as_os_str().len().cmp(&a.path().as_os_str().len()),
+        KeepRule::Lexical => a.path().cmp(b.path()),
+        KeepRule::DirectoryOrder => a.root.cmp(&b.root),
+    }
+}
+
+fn compare_for_keeping(a: &AugDirEntry, b: &AugDirEntry, options: &Options) -> Ordering {
+    // Reference files always come first, as they're never acted on; files
+    // only reached through symlinks come last, as they weren't asked about.
+    let is_reference = |dent: &AugDirEntry| options.is_reference_root(dent.root);
+    a.external
+        .cmp(&b.external)
+        .then_with(|| is_reference(b).cmp(&is_reference(a)))
+        .then_with(|| keep_under_rank(a.path(), options).cmp(&keep_under_rank(b.path(), options)))
+        .then_with(|| {
+            options
+                .keep_rules
+                .iter()
+                .map(|rule| compare_by_rule(rule, a, b))
+                .find(|ord| ord.is_ne())
+                .unwrap_or(Ordering::Equal)
+        })
+        .then_with(|| a.path().cmp(b.path()))
+}
+
+/// Sort the files of a duplicate group so the one to keep comes first.
+///
+/// Files under `--reference` roots come first, then preferred directories
+/// (`--keep-under`), then the `--keep` rules in the order given; any
+/// remaining ties are broken by path.
+pub fn sort_by_keep_preference(dents: &mut [&AugDirEntry], options: &Options) {
+    dents.sort_by(|a, b| compare_for_keeping(a, b, options));
+}
@@ -1,7 +1,27 @@
+pub mod action;
+pub mod apply;
+pub mod cache;
 pub mod cli;
+pub mod dirs;
 pub mod find;
+pub mod globs;
 pub mod hash;
+pub mod hasher;
 pub mod interrupt;
+pub mod journal;
+pub mod keep;
+pub mod mounts;
 pub mod options;
 pub mod output;
 pub mod parse_size;
+pub mod parse_time;
+pub mod read;
+pub mod reflink;
+pub mod report_path;
+pub mod restore;
+pub mod run;
+pub mod script;
+pub mod verify;
+pub mod walk;
+
+pub use run::run;
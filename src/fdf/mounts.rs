@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// A mounted filesystem, as listed in `/proc/self/mountinfo`.
+pub struct Mount {
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    /// Device id of the filesystem, as in `st_dev`.
+    pub dev: u64,
+}
+
+/// Undo the octal escapes (`\040` for a space, etc.) used in `/proc/self/mountinfo`.
+#[cfg(target_os = "linux")]
+fn unescape_mount_field(field: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        if field[i] == b'\\' && i + 4 <= field.len() {
+            let octal = std::str::from_utf8(&field[i + 1..i + 4]).ok();
+            if let Some(b) = octal.and_then(|o| u8::from_str_radix(o, 8).ok()) {
+                out.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(field[i]);
+        i += 1;
+    }
+    out
+}
+
+/// Parse a `/proc/self/mountinfo` line: mount and parent ids, `major:minor`,
+/// root, mount point and options, optional fields up to a `-`, then the type.
+#[cfg(target_os = "linux")]
+fn parse_mountinfo_line(line: &[u8]) -> Option<Mount> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    let mut fields = line.split(|&b| b == b' ');
+    let _mount_id = fields.next()?;
+    let _parent_id = fields.next()?;
+    let (major, minor) = std::str::from_utf8(fields.next()?).ok()?.split_once(':')?;
+    let _root = fields.next()?;
+    let mount_point = fields.next()?;
+    let fs_type = fields.find(|&field| field == b"-").and(fields.next())?;
+    Some(Mount {
+        mount_point: PathBuf::from(OsString::from_vec(unescape_mount_field(mount_point))),
+        fs_type: String::from_utf8_lossy(fs_type).into_owned(),
+        dev: libc::makedev(major.parse().ok()?, minor.parse().ok()?),
+    })
+}
+
+#[cfg(target_os = "linux")]
+pub fn read_mounts() -> std::io::Result<Vec<Mount>> {
+    let text = std::fs::read("/proc/self/mountinfo")?;
+    Ok(text
+        .split(|&b| b == b'\n')
+        .filter_map(parse_mountinfo_line)
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_mounts() -> std::io::Result<Vec<Mount>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "listing mounts is only supported on Linux",
+    ))
+}
+
+/// Whether `fs_type` is one of `excluded`; `fuse` also matches subtypes like `fuse.sshfs`.
+fn is_excluded_type(fs_type: &str, excluded: &[String]) -> bool {
+    excluded.iter().any(|ex| {
+        fs_type == ex
+            || fs_type
+                .strip_prefix(ex.as_str())
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+/// Device ids of all mounted filesystems whose type is in `excluded`.
+///
+/// Mount points aren't looked at, as they may hang (e.g. stale NFS mounts).
+pub fn excluded_devices(excluded: &[String]) -> HashSet<u64> {
+    if excluded.is_empty() {
+        return HashSet::new();
+    }
+    let mounts = match read_mounts() {
+        Ok(mounts) => mounts,
+        Err(e) => {
+            eprintln!(
+                "[!] Unable to list mounts to exclude filesystem types: {}",
+                e
+            );
+            return HashSet::new();
+        }
+    };
+    mounts
+        .iter()
+        .filter(|mount| is_excluded_type(&mount.fs_type, excluded))
+        .map(|mount| mount.dev)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parses_mountinfo_lines() {
+        let line = br"36 35 98:0 /mnt1 /mnt/my\040disk rw,noatime master:1 - ext3 /dev/root rw";
+        let mount = parse_mountinfo_line(line).unwrap();
+        assert_eq!(mount.mount_point, PathBuf::from("/mnt/my disk"));
+        assert_eq!(mount.fs_type, "ext3");
+        assert_eq!(mount.dev, libc::makedev(98, 0));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mount_devices_match_stat() {
+        use std::os::unix::fs::MetadataExt;
+        let dev = std::fs::metadata("/proc").unwrap().dev();
+        let mounts = read_mounts().unwrap();
+        let proc = mounts
+            .iter()
+            .rfind(|mount| mount.mount_point == std::path::Path::new("/proc"))
+            .unwrap();
+        assert_eq!(proc.dev, dev);
+    }
+
+    #[test]
+    fn fuse_matches_subtypes() {
+        let excluded = ["fuse".to_string()];
+        assert!(is_excluded_type("fuse.sshfs", &excluded));
+        assert!(!is_excluded_type("fuseblk2", &excluded));
+    }
+}
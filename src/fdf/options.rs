@@ -1,13 +1,11 @@
+use super::globs::GlobFilter;
+use super::hasher::HashAlgorithm;
+use super::report_path::path_to_bytes;
 use clap::ValueEnum;
-use regex::RegexSet;
-use walkdir::DirEntry;
-
-#[derive(Clone, PartialEq, Eq, Debug, ValueEnum)]
-pub enum HashAlgorithm {
-    Blake3,
-    Sha256,
-    Xxh64,
-}
+use regex::bytes::RegexSet;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 #[derive(PartialEq, Eq, Debug, Hash)]
 pub enum ReportOption {
@@ -23,50 +21,311 @@ pub enum NameGroupingOption {
     SingleGroupWhenNoExtension,
 }
 
+#[derive(Clone, Debug, PartialEq, ValueEnum)]
+pub enum HardlinkOption {
+    /// Report sets of hardlinks to the same file in their own section
+    List,
+    /// Leave the hardlink section out of the reports
+    Ignore,
+}
+
+#[derive(Clone, Debug, PartialEq, ValueEnum)]
+pub enum SymlinkOption {
+    /// Ignore symlinks entirely
+    Skip,
+    /// Follow symlinks, hashing each target once
+    Follow,
+    /// Don't follow symlinks, but list those pointing at found files
+    Report,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum IoMode {
+    /// Read files through a buffer, dropping large ones from the page cache
+    /// afterwards (on Linux)
+    Auto,
+    /// Read files through a buffer
+    Buffered,
+    /// Memory-map files, hashing large ones on several threads with BLAKE3;
+    /// a file truncated while being hashed crashes fdf
+    Mmap,
+    /// Keep files out of the page cache, with O_DIRECT or by dropping them after reading (Linux only)
+    Direct,
+}
+
+#[derive(Clone, Debug, PartialEq, ValueEnum)]
+pub enum DuplicateDirsOption {
+    /// Only report duplicate files
+    Off,
+    /// Report duplicate directories as well as the files in them
+    Also,
+    /// Report duplicate directories instead of the files in them
+    Instead,
+}
+
+/// A `--perm` test on permission bits, as in find(1).
+#[derive(Clone, Debug, PartialEq)]
+pub enum PermFilter {
+    /// The permission bits are exactly these (`MODE`).
+    Exact(u32),
+    /// All of these bits are set (`-MODE`).
+    All(u32),
+    /// Any of these bits is set (`/MODE`).
+    Any(u32),
+}
+
+impl PermFilter {
+    pub fn matches(&self, mode: u32) -> bool {
+        let mode = mode & 0o7777;
+        match *self {
+            PermFilter::Exact(bits) => mode == bits,
+            PermFilter::All(bits) => mode & bits == bits,
+            PermFilter::Any(bits) => bits == 0 || mode & bits != 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, ValueEnum)]
+pub enum LinkMode {
+    Hard,
+    Symlink,
+    Reflink,
+}
+
+#[derive(Clone, Debug, PartialEq, ValueEnum)]
+pub enum ActionKind {
+    Delete,
+    Hardlink,
+    Symlink,
+    Reflink,
+}
+
+/// What to do with the non-kept files of each verified duplicate group.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Link(LinkMode),
+    Delete,
+    Move(PathBuf),
+}
+
+impl Action {
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Action::Link(LinkMode::Hard) => "link",
+            Action::Link(LinkMode::Symlink) => "symlink",
+            Action::Link(LinkMode::Reflink) => "reflink",
+            Action::Delete => "delete",
+            Action::Move(_) => "move",
+        }
+    }
+
+    pub fn past_verb(&self) -> &'static str {
+        match self {
+            Action::Link(LinkMode::Hard) => "linked",
+            Action::Link(LinkMode::Symlink) => "symlinked",
+            Action::Link(LinkMode::Reflink) => "reflinked",
+            Action::Delete => "deleted",
+            Action::Move(_) => "moved",
+        }
+    }
+
+    /// Whether the action loses data that should be recorded in a journal first.
+    pub fn needs_journal(&self) -> bool {
+        matches!(self, Action::Delete | Action::Move(_))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, ValueEnum)]
+pub enum KeepRule {
+    Oldest,
+    Newest,
+    ShortestPath,
+    LongestPath,
+    Lexical,
+    DirectoryOrder,
+}
+
 #[derive(Debug)]
 pub struct Options {
-    pub directories: Vec<String>,
+    /// Roots to search, with the `--reference` roots last; a single empty
+    /// path when reading `--files-from`.
+    pub directories: Vec<PathBuf>,
+    /// Number of `--reference` roots at the end of `directories`.
+    pub n_reference: usize,
+    /// File to read paths from instead of walking `directories`, `-` for stdin.
+    pub files_from: Option<String>,
+    /// Whether `files_from` is NUL-separated rather than newline-separated.
+    pub null_separated: bool,
     pub file_include_regexes: RegexSet,
+    /// `--glob`/`--exclude-glob` filters, one per directory in `directories`.
+    pub globs: Vec<GlobFilter>,
     pub file_exclude_regexes: RegexSet,
     pub dir_include_regexes: RegexSet,
     pub dir_exclude_regexes: RegexSet,
     pub verbosity: u64,
     pub hash_bytes: u64,
+    pub prefix_bytes: u64,
+    pub suffix_bytes: u64,
     pub hash_algorithm: HashAlgorithm,
+    /// Key for `--hash-algorithm blake3-keyed`.
+    pub hash_key: Option<[u8; 32]>,
+    pub io_mode: IoMode,
     pub report_json: ReportOption,
     pub report_human: ReportOption,
     pub report_file_list: ReportOption,
+    pub report_unique: ReportOption,
+    /// Only list unique files under these directories.
+    pub unique_under: Vec<PathBuf>,
+    pub report_script: ReportOption,
+    pub script_action: ActionKind,
     pub name_grouping: NameGroupingOption,
     pub min_size: u64,
     pub max_size: u64,
+    /// Only consider files modified before this time.
+    pub older_than: Option<SystemTime>,
+    /// Only consider files modified after this time.
+    pub newer_than: Option<SystemTime>,
+    /// Only consider files whose status changed before this time.
+    pub changed_before: Option<SystemTime>,
+    /// Only consider files whose status changed after this time.
+    pub changed_after: Option<SystemTime>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub perm: Option<PermFilter>,
+    pub verify: bool,
+    pub hash_cache: Option<String>,
+    pub hardlinks: HardlinkOption,
+    pub symlinks: SymlinkOption,
+    pub duplicate_dirs: DuplicateDirsOption,
+    /// Minimum similarity (0 to 1) for differing directories to be reported as near-duplicates.
+    pub dir_similarity: f64,
+    pub one_file_system: bool,
+    pub exclude_fs_types: Vec<String>,
+    pub threads: usize,
+    /// Whether to honor `.gitignore`, `.ignore` and `.fdfignore` files, with
+    /// `--respect-ignore`; like git, `.gitignore` only counts inside a repository.
+    pub ignore_files: bool,
+    pub action: Option<Action>,
+    pub dry_run: bool,
+    pub journal: String,
+    pub keep_rules: Vec<KeepRule>,
+    pub keep_under: Vec<PathBuf>,
+}
+
+/// Whether `time` is strictly between the given bounds; an unknown time never is.
+fn is_time_in_range(
+    time: Option<SystemTime>,
+    before: Option<SystemTime>,
+    after: Option<SystemTime>,
+) -> bool {
+    if before.is_none() && after.is_none() {
+        return true;
+    }
+    time.is_some_and(|time| {
+        before.is_none_or(|before| time < before) && after.is_none_or(|after| time > after)
+    })
 }
 
 impl Options {
-    pub fn is_file_included(&self, path_str: &str) -> bool {
-        if !self.file_exclude_regexes.is_empty() && self.file_exclude_regexes.is_match(path_str) {
+    pub fn is_file_included(&self, path: &Path, root: usize) -> bool {
+        let bytes = path_to_bytes(path);
+        if !self.file_exclude_regexes.is_empty() && self.file_exclude_regexes.is_match(&bytes) {
             return false;
         }
-        if !self.file_include_regexes.is_empty() && !self.file_include_regexes.is_match(path_str) {
+        if !self.file_include_regexes.is_empty() && !self.file_include_regexes.is_match(&bytes) {
             return false;
         }
-        true
+        self.globs[root].is_file_included(path)
     }
 
-    pub fn is_dir_included(&self, path_str: &str) -> bool {
-        if !self.dir_exclude_regexes.is_empty() && self.dir_exclude_regexes.is_match(path_str) {
+    pub fn is_dir_included(&self, path: &Path, root: usize) -> bool {
+        let bytes = path_to_bytes(path);
+        if !self.dir_exclude_regexes.is_empty() && self.dir_exclude_regexes.is_match(&bytes) {
             return false;
         }
-        if !self.dir_include_regexes.is_empty() && !self.dir_include_regexes.is_match(path_str) {
+        if !self.dir_include_regexes.is_empty() && !self.dir_include_regexes.is_match(&bytes) {
             return false;
         }
+        self.globs[root].is_dir_included(path)
+    }
+
+    /// Index of the innermost root `path` is under, so that e.g. a
+    /// `--reference` root inside a searched one takes precedence.
+    pub fn root_index(&self, path: &Path) -> usize {
+        let mut best: Option<(usize, usize)> = None;
+        for (i, dir) in self.directories.iter().enumerate() {
+            let depth = dir.components().count();
+            if path.starts_with(dir) && best.is_none_or(|(_, best_depth)| depth > best_depth) {
+                best = Some((i, depth));
+            }
+        }
+        best.map_or(0, |(i, _)| i)
+    }
+
+    /// Whether the `root`th directory is a `--reference` root, whose files are never acted on.
+    pub fn is_reference_root(&self, root: usize) -> bool {
+        root >= self.directories.len() - self.n_reference
+    }
+
+    /// Whether a file's metadata passes the type, time, owner and permission filters.
+    pub fn is_metadata_included(&self, meta: &Metadata) -> bool {
+        meta.is_file()
+            && is_time_in_range(meta.modified().ok(), self.older_than, self.newer_than)
+            && self.is_unix_metadata_included(meta)
+    }
+
+    #[cfg(unix)]
+    fn is_unix_metadata_included(&self, meta: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        use std::time::{Duration, UNIX_EPOCH};
+        let ctime = u64::try_from(meta.ctime())
+            .ok()
+            .map(|secs| UNIX_EPOCH + Duration::new(secs, meta.ctime_nsec() as u32));
+        is_time_in_range(ctime, self.changed_before, self.changed_after)
+            && self.uid.is_none_or(|uid| meta.uid() == uid)
+            && self.gid.is_none_or(|gid| meta.gid() == gid)
+            && self
+                .perm
+                .as_ref()
+                .is_none_or(|perm| perm.matches(meta.mode()))
+    }
+
+    /// Change times, owners and permission bits are only available on Unix.
+    #[cfg(not(unix))]
+    fn is_unix_metadata_included(&self, _meta: &Metadata) -> bool {
         true
     }
 
-    pub fn is_entry_included(&self, dent: &DirEntry) -> bool {
-        if dent.file_type().is_dir() {
-            self.is_dir_included(dent.path().to_str().unwrap())
+    /// Whether an entry found under the `root`th directory passes all filters.
+    pub fn is_entry_included(&self, path: &Path, is_dir: bool, root: usize) -> bool {
+        if is_dir {
+            self.is_dir_included(path, root)
         } else {
-            self.is_file_included(dent.path().to_str().unwrap())
+            self.is_file_included(path, root)
         }
     }
 }
+
+#[derive(Debug)]
+pub struct RestoreOptions {
+    pub journal: String,
+    pub dry_run: bool,
+}
+
+#[derive(Debug)]
+pub struct ApplyOptions {
+    pub report: String,
+    /// Key to recompute `blake3-keyed` digests with.
+    pub hash_key: Option<[u8; 32]>,
+    pub io_mode: IoMode,
+    pub action: Action,
+    pub dry_run: bool,
+    pub journal: String,
+}
+
+#[derive(Debug)]
+pub enum Invocation {
+    Find(Box<Options>),
+    Apply(ApplyOptions),
+    Restore(RestoreOptions),
+}
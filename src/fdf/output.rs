@@ -1,39 +1,168 @@
-use serde::Serialize;
+use super::hasher::HashAlgorithm;
+use super::report_path::ReportPath;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize)]
+/// Version of the JSON report format; bumped on incompatible changes.
+pub const REPORT_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FindStats {
     pub interrupted: bool,
     pub n_bytes: u64,
     pub n_dirs: u64,
     pub n_files: u64,
+    pub n_hardlinks: u64,
+    #[serde(default)]
+    pub n_symlinks: u64,
     pub n_precull_groups: u64,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct HashStats {
     pub interrupted: bool,
     pub n_bytes: u64,
     pub n_files: u64,
     pub n_groups: u64,
+    pub n_cache_hits: u64,
+    pub n_cache_misses: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileResult {
+    pub path: ReportPath,
+    /// The `--directory` root the file was found under; none for `--files-from`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<ReportPath>,
+    pub mtime_ns: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<u64>,
+    /// Whether the file is under a `--reference` root, so must not be acted on.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reference: bool,
+    /// Symlinks pointing at this file, with `--symlinks report`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub symlinks: Vec<ReportPath>,
+}
+
+#[cfg(test)]
+impl FileResult {
+    /// A file found under `root`, with its current mtime if it exists.
+    pub fn for_test(path: &std::path::Path, root: Option<&std::path::Path>) -> FileResult {
+        FileResult {
+            path: ReportPath::new(path),
+            root: root.map(ReportPath::new),
+            mtime_ns: std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok()
+                .map(super::cache::mtime_ns),
+            device: None,
+            reference: false,
+            symlinks: Vec::new(),
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct HashGroupResult {
     pub hash: String,
-    pub files: Vec<String>,
+    pub files: Vec<FileResult>,
+    pub keeper: Option<ReportPath>,
+    pub verified: bool,
+    /// Which of the groups sharing this hash this is (from 1), when `--verify`
+    /// found files with the same hash to differ or couldn't read some of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<usize>,
+    /// Whether the files live on more than one device, so can't all be hardlinked together.
+    #[serde(default)]
+    pub cross_device: bool,
 }
 
-#[derive(Debug, Serialize)]
+impl HashGroupResult {
+    /// The hash, with the part number for groups split up by `--verify`.
+    pub fn label(&self) -> String {
+        match self.part {
+            Some(part) => format!("{}#{}", self.hash, part),
+            None => self.hash.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct KeyGroupResult {
     pub size: u64,
     pub identifier: String,
     pub hash_groups: Vec<HashGroupResult>,
+    /// Files told apart from the rest of the group by a partial hash, so never hashed in full.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub distinct: Vec<FileResult>,
+    pub n_files: u64,
+}
+
+#[cfg(test)]
+impl KeyGroupResult {
+    /// A key group of `size`-byte files with a verified hash group for each
+    /// list of files, keeping the first file of each.
+    pub fn for_test(size: u64, hash_groups: Vec<Vec<FileResult>>) -> KeyGroupResult {
+        KeyGroupResult {
+            size,
+            identifier: "txt".to_string(),
+            n_files: hash_groups.iter().map(|files| files.len() as u64).sum(),
+            hash_groups: hash_groups
+                .into_iter()
+                .enumerate()
+                .map(|(i, files)| HashGroupResult {
+                    hash: format!("test-{}", i),
+                    keeper: Some(files[0].path.clone()),
+                    files,
+                    verified: true,
+                    part: None,
+                    cross_device: false,
+                })
+                .collect(),
+            distinct: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HardlinkSetResult {
+    pub size: u64,
+    pub files: Vec<ReportPath>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DirResult {
+    pub path: ReportPath,
+    /// Total size of the found files in the directory tree.
+    pub size: u64,
     pub n_files: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reference: bool,
+}
+
+/// Directories with identical trees of found files, or (with a similarity
+/// below 1) a pair of directories sharing most of their contents.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DirGroupResult {
+    /// Whether the trees are identical; similarity alone can't tell, as
+    /// differing trees may still share all of their bytes.
+    #[serde(default)]
+    pub identical: bool,
+    pub similarity: f64,
+    pub dirs: Vec<DirResult>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct GrandResult<'a> {
-    pub find_stats: &'a FindStats,
-    pub hash_stats: &'a HashStats,
-    pub key_groups: &'a Vec<KeyGroupResult>,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GrandResult {
+    pub version: u32,
+    pub hash_algorithm: HashAlgorithm,
+    pub hash_bytes: u64,
+    pub find_stats: FindStats,
+    pub hash_stats: HashStats,
+    pub key_groups: Vec<KeyGroupResult>,
+    pub hardlink_sets: Vec<HardlinkSetResult>,
+    #[serde(default)]
+    pub dangling_symlinks: Vec<ReportPath>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dir_groups: Vec<DirGroupResult>,
 }
@@ -0,0 +1,25 @@
+use anyhow;
+use std::time::SystemTime;
+
+/// Parse a point in time given either as an age relative to now (`30d`,
+/// `12h`, `1week`), a date (`2024-01-01`, midnight UTC) or a date and time
+/// (`2024-01-01T12:00:00`, UTC).
+pub fn parse_time_string(s: &str) -> anyhow::Result<SystemTime> {
+    let s = s.trim();
+    if let Ok(age) = humantime::parse_duration(s) {
+        return SystemTime::now()
+            .checked_sub(age)
+            .ok_or_else(|| anyhow::anyhow!("Time too far in the past: {}", s));
+    }
+    let datetime = if s.len() == 10 {
+        format!("{} 00:00:00", s)
+    } else {
+        s.to_string()
+    };
+    humantime::parse_rfc3339_weak(&datetime).map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid time string (expected e.g. 30d or 2024-01-01): {}",
+            s
+        )
+    })
+}
@@ -0,0 +1,183 @@
+use super::hasher::FileHasher;
+use super::options::IoMode;
+use memmap2::MmapOptions;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Mapped ranges at least this big are hashed on several threads, where the algorithm can.
+const PARALLEL_THRESHOLD: usize = 1024 * 1024;
+
+/// Size and alignment of the buffer for `O_DIRECT` reads.
+#[cfg(target_os = "linux")]
+const DIRECT_BUF_SIZE: usize = 1024 * 1024;
+#[cfg(target_os = "linux")]
+const DIRECT_ALIGN: usize = 4096;
+
+/// Ranges at least this big are dropped from the page cache after reading
+/// with `--io-mode auto`, so a big scan doesn't evict everything else.
+#[cfg(target_os = "linux")]
+const AUTO_DROP_CACHE_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Feed everything `reader` yields to `update`, `buf_cap` bytes at a time.
+fn read_into(
+    reader: &mut impl Read,
+    buf_cap: usize,
+    mut update: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let mut buf = vec![0u8; buf_cap];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_buffered(
+    file: &mut File,
+    hasher: &mut dyn FileHasher,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    if offset > 0 {
+        file.seek(SeekFrom::Start(offset))?;
+    }
+    let buf_cap = len.clamp(8_192, 524_288) as usize;
+    read_into(&mut file.take(len), buf_cap, |buf| hasher.update(buf))
+}
+
+fn read_mmap(path: &Path, hasher: &mut dyn FileHasher, offset: u64, len: u64) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    // Touching a mapping past the end of the file is fatal, so don't map
+    // files that have shrunk since they were found (or empty ranges, which can't be).
+    if len == 0 || file.metadata()?.len() < offset + len {
+        return read_buffered(&mut file, hasher, offset, len);
+    }
+    // SAFETY: the mapping is only read from, and a file whose contents change
+    // while it's being hashed just gets a meaningless digest. A file truncated
+    // while mapped, though, makes reading past its new end raise SIGBUS and
+    // kill the process, which is why only `--io-mode mmap` maps files.
+    let map = match unsafe {
+        MmapOptions::new()
+            .offset(offset)
+            .len(len as usize)
+            .map(&file)
+    } {
+        Ok(map) => map,
+        // Not all files can be mapped, e.g. on some network filesystems.
+        Err(_) => return read_buffered(&mut file, hasher, offset, len),
+    };
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+    if map.len() >= PARALLEL_THRESHOLD {
+        hasher.update_parallel(&map);
+    } else {
+        hasher.update(&map);
+    }
+    Ok(())
+}
+
+/// Read through the page cache, then ask the kernel to drop the pages read.
+#[cfg(target_os = "linux")]
+fn read_dropping_cache(
+    path: &Path,
+    hasher: &mut dyn FileHasher,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mut file = File::open(path)?;
+    read_buffered(&mut file, hasher, offset, len)?;
+    // Only advice, so there's nothing to do if it fails.
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        );
+    }
+    Ok(())
+}
+
+/// Read with `O_DIRECT`, bypassing the page cache, falling back to dropping
+/// the pages afterwards on filesystems (like tmpfs) that don't support it.
+#[cfg(target_os = "linux")]
+fn read_direct(path: &Path, hasher: &mut dyn FileHasher, offset: u64, len: u64) -> io::Result<()> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::{FileExt, OpenOptionsExt};
+    let file = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            return read_dropping_cache(path, hasher, offset, len)
+        }
+        Err(e) => return Err(e),
+    };
+    // O_DIRECT needs the buffer, offsets and lengths aligned to the block size.
+    let mut storage = vec![0u8; DIRECT_BUF_SIZE + DIRECT_ALIGN];
+    let start = storage.as_ptr().align_offset(DIRECT_ALIGN);
+    let buf = &mut storage[start..start + DIRECT_BUF_SIZE];
+    let end = offset + len;
+    let first = offset - offset % DIRECT_ALIGN as u64;
+    let mut pos = first;
+    while pos < end {
+        // Lengths must be aligned too, so the last read may go past `end`.
+        let want = (end - pos).next_multiple_of(DIRECT_ALIGN as u64);
+        let want = want.min(buf.len() as u64) as usize;
+        let n = match file.read_at(&mut buf[..want], pos) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && pos == first => {
+                return read_dropping_cache(path, hasher, offset, len)
+            }
+            Err(e) => return Err(e),
+        };
+        let skip = offset.saturating_sub(pos) as usize;
+        let take = (end - pos).min(n as u64) as usize;
+        if skip < take {
+            hasher.update(&buf[skip..take]);
+        }
+        pos += n as u64;
+        if pos % DIRECT_ALIGN as u64 != 0 {
+            // Only the end of the file leaves the position unaligned, and
+            // O_DIRECT can't read on from there.
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_direct(path: &Path, hasher: &mut dyn FileHasher, offset: u64, len: u64) -> io::Result<()> {
+    read_buffered(&mut File::open(path)?, hasher, offset, len)
+}
+
+/// Feed `len` bytes of a file from `offset` to `hasher`, reading it as `mode` says.
+pub fn hash_file_range(
+    path: &Path,
+    hasher: &mut dyn FileHasher,
+    offset: u64,
+    len: u64,
+    mode: IoMode,
+) -> io::Result<()> {
+    match mode {
+        #[cfg(target_os = "linux")]
+        IoMode::Auto if len >= AUTO_DROP_CACHE_THRESHOLD => {
+            read_dropping_cache(path, hasher, offset, len)
+        }
+        IoMode::Auto | IoMode::Buffered => {
+            read_buffered(&mut File::open(path)?, hasher, offset, len)
+        }
+        IoMode::Mmap => read_mmap(path, hasher, offset, len),
+        IoMode::Direct => read_direct(path, hasher, offset, len),
+    }
+}
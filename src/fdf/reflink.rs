@@ -0,0 +1,196 @@
+use std::io;
+use std::path::Path;
+
+pub enum ReflinkOutcome {
+    /// The destination's extents were replaced by the source's.
+    Deduplicated,
+    /// The files were already sharing all of their extents.
+    AlreadyShared,
+}
+
+/// Make `dest` share `src`'s extents, using the kernel's `FIDEDUPERANGE`.
+///
+/// Unlike `FICLONE`, this keeps `dest`'s inode and metadata, and the kernel
+/// refuses to share extents whose contents differ, so no data can be lost
+/// even if the files were modified since they were hashed.
+#[cfg(target_os = "linux")]
+pub fn dedupe_file(src: &Path, dest: &Path, size: u64) -> io::Result<ReflinkOutcome> {
+    use std::fs::{File, OpenOptions};
+    let src_file = File::open(src)?;
+    let dest_file = match OpenOptions::new().write(true).open(dest) {
+        Ok(f) => f,
+        // Recent kernels allow deduplicating into files the user owns without write access.
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => File::open(dest)?,
+        Err(e) => return Err(e),
+    };
+    if linux::is_sharing_extents(&src_file, &dest_file, size).unwrap_or(false) {
+        return Ok(ReflinkOutcome::AlreadyShared);
+    }
+    linux::dedupe_range(&src_file, &dest_file, size).map_err(explain_error)?;
+    Ok(ReflinkOutcome::Deduplicated)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn dedupe_file(_src: &Path, _dest: &Path, _size: u64) -> io::Result<ReflinkOutcome> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn explain_error(e: io::Error) -> io::Error {
+    let message = match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL) => {
+            "filesystem does not support reflinks"
+        }
+        Some(libc::EXDEV) => "not on the same filesystem as the kept file",
+        _ => return e,
+    };
+    io::Error::new(io::ErrorKind::Unsupported, format!("{} ({})", message, e))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    // From linux/fs.h and linux/fiemap.h.
+    const FIDEDUPERANGE: u64 = 0xC018_9436;
+    const FS_IOC_FIEMAP: u64 = 0xC020_660B;
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+    const FIEMAP_FLAG_SYNC: u32 = 0x0001;
+    const FIEMAP_EXTENT_LAST: u32 = 0x0001;
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x0002;
+    const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x0200;
+
+    /// Btrfs refuses to deduplicate more than this at a time.
+    const MAX_DEDUPE_LEN: u64 = 16 * 1024 * 1024;
+    /// Files with more extents than this are assumed not to be shared.
+    const MAX_EXTENTS: usize = 512;
+
+    #[repr(C)]
+    struct FileDedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        info: [FileDedupeRangeInfo; 1],
+    }
+
+    #[repr(C)]
+    struct FileDedupeRangeInfo {
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct FiemapExtent {
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_reserved64: [u64; 2],
+        fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fm_extents: [FiemapExtent; MAX_EXTENTS],
+    }
+
+    pub fn dedupe_range(src: &File, dest: &File, size: u64) -> io::Result<()> {
+        let mut offset: u64 = 0;
+        while offset < size {
+            let mut range = FileDedupeRange {
+                src_offset: offset,
+                src_length: (size - offset).min(MAX_DEDUPE_LEN),
+                dest_count: 1,
+                reserved1: 0,
+                reserved2: 0,
+                info: [FileDedupeRangeInfo {
+                    dest_fd: dest.as_raw_fd() as i64,
+                    dest_offset: offset,
+                    bytes_deduped: 0,
+                    status: 0,
+                    reserved: 0,
+                }],
+            };
+            let ret = unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let info = &range.info[0];
+            if info.status < 0 {
+                return Err(io::Error::from_raw_os_error(-info.status));
+            }
+            if info.status == FILE_DEDUPE_RANGE_DIFFERS {
+                return Err(io::Error::other("file contents differ"));
+            }
+            if info.bytes_deduped == 0 {
+                return Err(io::Error::other("kernel deduplicated no data"));
+            }
+            offset += info.bytes_deduped;
+        }
+        Ok(())
+    }
+
+    fn physical_extents(f: &File, size: u64) -> io::Result<Option<Vec<(u64, u64, u64)>>> {
+        let mut fiemap = Box::new(Fiemap {
+            fm_start: 0,
+            fm_length: size,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_mapped_extents: 0,
+            fm_extent_count: MAX_EXTENTS as u32,
+            fm_reserved: 0,
+            fm_extents: [FiemapExtent::default(); MAX_EXTENTS],
+        });
+        let ret = unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_FIEMAP as _, &mut *fiemap) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let extents = &fiemap.fm_extents[..fiemap.fm_mapped_extents as usize];
+        if extents
+            .last()
+            .is_none_or(|ext| ext.fe_flags & FIEMAP_EXTENT_LAST == 0)
+        {
+            // Either no extents at all, or too many to fit in the buffer.
+            return Ok(None);
+        }
+        if extents
+            .iter()
+            .any(|ext| ext.fe_flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DATA_INLINE) != 0)
+        {
+            // Physical locations aren't meaningful for these.
+            return Ok(None);
+        }
+        Ok(Some(
+            extents
+                .iter()
+                .map(|ext| (ext.fe_logical, ext.fe_physical, ext.fe_length))
+                .collect(),
+        ))
+    }
+
+    /// Whether both files are backed by exactly the same physical extents.
+    pub fn is_sharing_extents(a: &File, b: &File, size: u64) -> io::Result<bool> {
+        Ok(
+            match (physical_extents(a, size)?, physical_extents(b, size)?) {
+                (Some(ea), Some(eb)) => ea == eb,
+                _ => false,
+            },
+        )
+    }
+}
@@ -0,0 +1,132 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A path as written into reports and journals.
+///
+/// Paths that are valid UTF-8 are written as plain strings; others are
+/// written as an object with a human-readable escaped form and the raw
+/// bytes in base64, so they can be read back losslessly.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ReportPath(pub PathBuf);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum EncodedPath {
+    Plain(String),
+    Raw { escaped: String, base64: String },
+}
+
+#[cfg(unix)]
+pub fn path_to_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn path_to_bytes(path: &Path) -> Cow<'_, [u8]> {
+    // Paths that aren't valid Unicode can't be represented as bytes here;
+    // the lossy form is the best we can do.
+    match path.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+#[cfg(unix)]
+pub fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+pub fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// Render bytes as UTF-8, escaping invalid sequences as `\xNN`.
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        escaped.push_str(chunk.valid());
+        for b in chunk.invalid() {
+            write!(escaped, "\\x{:02x}", b).unwrap();
+        }
+    }
+    escaped
+}
+
+/// Write a path and a newline, as raw bytes so that non-UTF-8 paths are kept intact.
+pub fn write_path_line(stream: &mut dyn io::Write, path: &Path) -> io::Result<()> {
+    stream.write_all(&path_to_bytes(path))?;
+    stream.write_all(b"\n")
+}
+
+impl ReportPath {
+    pub fn new(path: &Path) -> ReportPath {
+        ReportPath(path.to_path_buf())
+    }
+
+    pub fn as_bytes(&self) -> Cow<'_, [u8]> {
+        path_to_bytes(&self.0)
+    }
+}
+
+impl Deref for ReportPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ReportPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<OsStr> for ReportPath {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_os_str()
+    }
+}
+
+impl Serialize for ReportPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.to_str() {
+            Some(s) => serializer.serialize_str(s),
+            None => {
+                let bytes = self.as_bytes();
+                EncodedPath::Raw {
+                    escaped: escape_bytes(&bytes),
+                    base64: BASE64.encode(&bytes),
+                }
+                .serialize(serializer)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ReportPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match EncodedPath::deserialize(deserializer)? {
+            EncodedPath::Plain(s) => Ok(ReportPath(PathBuf::from(s))),
+            EncodedPath::Raw { base64, .. } => {
+                let bytes = BASE64.decode(base64).map_err(D::Error::custom)?;
+                bytes_to_path(bytes)
+                    .map(ReportPath)
+                    .ok_or_else(|| D::Error::custom("path not representable on this platform"))
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use super::action::{move_file, temp_path_for};
+use super::cache::mtime_ns;
+use super::journal::{read_journal, JournalEntry};
+use super::options::RestoreOptions;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(Debug, Default)]
+pub struct RestoreStats {
+    pub n_restored: u64,
+    pub n_skipped: u64,
+    pub n_failed: u64,
+}
+
+/// Move a quarantined file back where it came from.
+fn restore_moved(entry: &JournalEntry) -> io::Result<()> {
+    let destination = entry
+        .destination
+        .as_ref()
+        .ok_or_else(|| io::Error::other("journal entry has no destination"))?;
+    if fs::symlink_metadata(destination)?.len() != entry.size {
+        return Err(io::Error::other(format!(
+            "{} has changed size since it was moved",
+            destination.display()
+        )));
+    }
+    move_file(destination, &entry.path)
+}
+
+/// Recreate a deleted file by copying the file that was kept in its stead.
+fn restore_deleted(entry: &JournalEntry) -> io::Result<()> {
+    let path: &Path = &entry.path;
+    let keeper_meta = fs::symlink_metadata(&entry.keeper)?;
+    if keeper_meta.len() != entry.size
+        || keeper_meta.modified().ok().map(mtime_ns) != entry.keeper_mtime_ns
+    {
+        return Err(io::Error::other(format!(
+            "{} has changed since scanning",
+            entry.keeper.display()
+        )));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp_path = temp_path_for(path);
+    let res = fs::copy(&entry.keeper, &temp_path).and_then(|_| {
+        let f = fs::OpenOptions::new().write(true).open(&temp_path)?;
+        if let Some(ns) = entry.mtime_ns.filter(|ns| *ns >= 0) {
+            f.set_modified(UNIX_EPOCH + Duration::from_nanos(ns as u64))?;
+        }
+        f.sync_all()?;
+        fs::rename(&temp_path, path)
+    });
+    if res.is_err() {
+        fs::remove_file(&temp_path).ok();
+    }
+    res
+}
+
+/// Undo the actions recorded in a journal, as far as possible.
+pub fn restore(options: &RestoreOptions) -> anyhow::Result<RestoreStats> {
+    let journal = read_journal(Path::new(&options.journal))
+        .map_err(|e| anyhow::anyhow!("Unable to read journal {}: {}", options.journal, e))?;
+    let restore_entry: fn(&JournalEntry) -> io::Result<()> = match journal.action.as_str() {
+        "move" => restore_moved,
+        "delete" => restore_deleted,
+        action => anyhow::bail!("Don't know how to restore action {:?}", action),
+    };
+    let mut stats = RestoreStats::default();
+    for entry in &journal.entries {
+        if entry.path.symlink_metadata().is_ok() {
+            stats.n_skipped += 1;
+            eprintln!("    skipped {}: already exists", entry.path.display());
+            continue;
+        }
+        if options.dry_run {
+            stats.n_restored += 1;
+            eprintln!("    would restore {}", entry.path.display());
+            continue;
+        }
+        match restore_entry(entry) {
+            Ok(()) => stats.n_restored += 1,
+            Err(e) => {
+                stats.n_failed += 1;
+                eprintln!("[!] Unable to restore {}: {}", entry.path.display(), e);
+            }
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::act_on_duplicates;
+    use crate::options::Action;
+    use crate::output::{FileResult, KeyGroupResult};
+    use tempfile::tempdir;
+
+    /// A key group of the given files, all with the contents `data`, keeping the first.
+    fn duplicates(root: &Path, names: &[&str], data: &str) -> KeyGroupResult {
+        let files = names
+            .iter()
+            .map(|name| {
+                let path = root.join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, data).unwrap();
+                FileResult::for_test(&path, Some(root))
+            })
+            .collect();
+        KeyGroupResult::for_test(data.len() as u64, vec![files])
+    }
+
+    fn restore_journal(journal: &Path) -> RestoreStats {
+        restore(&RestoreOptions {
+            journal: journal.to_str().unwrap().to_string(),
+            dry_run: false,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn moved_files_are_restored() {
+        let root = tempdir().unwrap();
+        let quarantine = tempdir().unwrap();
+        let journal = quarantine.path().join("journal.json");
+        let kgr = duplicates(root.path(), &["a.txt", "sub/b.txt", "sub/c.txt"], "data");
+        let action = Action::Move(quarantine.path().join("dupes"));
+        let stats = act_on_duplicates(&[kgr], &action, false, &journal).unwrap();
+        assert_eq!(stats.n_files, 2);
+        assert!(root.path().join("a.txt").exists());
+        assert!(!root.path().join("sub/b.txt").exists());
+        assert!(quarantine.path().join("dupes/sub/b.txt").exists());
+        assert!(quarantine.path().join("dupes/sub/c.txt").exists());
+
+        let stats = restore_journal(&journal);
+        assert_eq!((stats.n_restored, stats.n_failed), (2, 0));
+        assert_eq!(fs::read(root.path().join("sub/b.txt")).unwrap(), b"data");
+        assert_eq!(fs::read(root.path().join("sub/c.txt")).unwrap(), b"data");
+        assert!(!quarantine.path().join("dupes/sub/b.txt").exists());
+    }
+
+    #[test]
+    fn deleted_files_are_restored_from_the_keeper() {
+        let root = tempdir().unwrap();
+        let journal = root.path().join("journal.json");
+        let kgr = duplicates(root.path(), &["a.txt", "b.txt"], "data");
+        let mtime = kgr.hash_groups[0].files[1].mtime_ns;
+        act_on_duplicates(&[kgr], &Action::Delete, false, &journal).unwrap();
+        assert!(!root.path().join("b.txt").exists());
+
+        let stats = restore_journal(&journal);
+        assert_eq!((stats.n_restored, stats.n_failed), (1, 0));
+        let path = root.path().join("b.txt");
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        assert_eq!(
+            fs::metadata(&path).unwrap().modified().ok().map(mtime_ns),
+            mtime
+        );
+    }
+
+    #[test]
+    fn deleted_files_are_not_restored_from_an_edited_keeper() {
+        let root = tempdir().unwrap();
+        let journal = root.path().join("journal.json");
+        let kgr = duplicates(root.path(), &["a.txt", "b.txt"], "data");
+        act_on_duplicates(&[kgr], &Action::Delete, false, &journal).unwrap();
+        let keeper = root.path().join("a.txt");
+        let mtime = fs::metadata(&keeper).unwrap().modified().unwrap();
+        fs::write(&keeper, "edit").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&keeper)
+            .unwrap()
+            .set_modified(mtime + Duration::from_secs(1))
+            .unwrap();
+
+        let stats = restore_journal(&journal);
+        assert_eq!((stats.n_restored, stats.n_failed), (0, 1));
+        assert!(!root.path().join("b.txt").exists());
+    }
+
+    #[test]
+    fn existing_files_are_not_overwritten() {
+        let root = tempdir().unwrap();
+        let quarantine = tempdir().unwrap();
+        let journal = quarantine.path().join("journal.json");
+        let kgr = duplicates(root.path(), &["a.txt", "b.txt"], "data");
+        let action = Action::Move(quarantine.path().join("dupes"));
+        act_on_duplicates(&[kgr], &action, false, &journal).unwrap();
+        fs::write(root.path().join("b.txt"), "new").unwrap();
+
+        let stats = restore_journal(&journal);
+        assert_eq!((stats.n_restored, stats.n_skipped), (0, 1));
+        assert_eq!(fs::read(root.path().join("b.txt")).unwrap(), b"new");
+        assert!(quarantine.path().join("dupes/b.txt").exists());
+    }
+
+    #[test]
+    fn journals_are_not_overwritten() {
+        let root = tempdir().unwrap();
+        let journal = root.path().join("journal.json");
+        fs::write(&journal, "earlier run").unwrap();
+        let kgr = duplicates(root.path(), &["a.txt", "b.txt"], "data");
+        assert!(act_on_duplicates(&[kgr], &Action::Delete, false, &journal).is_err());
+        assert!(root.path().join("b.txt").exists());
+        assert_eq!(fs::read(&journal).unwrap(), b"earlier run");
+    }
+}
@@ -0,0 +1,562 @@
+use super::action::{act_on_duplicates, ActionStats};
+use super::cache::{mtime_ns, HashCache};
+use super::dirs::{drop_files_in_duplicate_dirs, find_duplicate_dirs};
+use super::find::{
+    find_files, AugDirEntry, FindResult, GroupKey, KeyToDentsMap, KeyToPathToDentMap,
+};
+use super::hash::hash_key_group;
+use super::interrupt::{check_and_reset_interrupt, is_interrupted};
+use super::keep::sort_by_keep_preference;
+use super::options::{
+    Action, DuplicateDirsOption, HardlinkOption, Options, ReportOption, SymlinkOption,
+};
+use super::output::*;
+use super::report_path::{write_path_line, ReportPath};
+use super::script::write_script;
+use super::verify::verify_group;
+use humansize::{format_size, DECIMAL};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+fn file_result(dent: &AugDirEntry, options: &Options) -> FileResult {
+    FileResult {
+        path: ReportPath::new(dent.path()),
+        root: Some(&options.directories[dent.root])
+            .filter(|root| !root.as_os_str().is_empty())
+            .map(|root| ReportPath::new(root)),
+        mtime_ns: dent.mtime.map(mtime_ns),
+        device: dent.dev(),
+        reference: dent.is_reference(options),
+        symlinks: dent.symlinks.iter().map(|p| ReportPath::new(p)).collect(),
+    }
+}
+
+fn process_key_group(
+    key: &GroupKey,
+    dents: &[AugDirEntry],
+    options: &Options,
+    cache: Option<&HashCache>,
+) -> KeyGroupResult {
+    let hashes = hash_key_group(key, dents, options, cache);
+    let mut hash_groups = Vec::new();
+    for (hash, dents) in hashes.groups {
+        let groups: Vec<(Vec<&AugDirEntry>, bool)> = if options.verify && dents.len() > 1 {
+            let verified = verify_group(&dents);
+            let unreadable = Some(verified.unreadable).filter(|dents| !dents.is_empty());
+            verified
+                .groups
+                .into_iter()
+                .map(|dents| (dents, true))
+                .chain(unreadable.map(|dents| (dents, false)))
+                .collect()
+        } else {
+            vec![(dents, false)]
+        };
+        let split = groups.len() > 1;
+        for (i, (mut dents, verified)) in groups.into_iter().enumerate() {
+            if dents.iter().all(|dent| dent.is_reference(options)) {
+                continue;
+            }
+            sort_by_keep_preference(&mut dents, options);
+            hash_groups.push(HashGroupResult {
+                hash: hash.to_string(),
+                keeper: if dents.len() > 1 {
+                    Some(ReportPath::new(dents[0].path()))
+                } else {
+                    None
+                },
+                files: dents
+                    .iter()
+                    .map(|dent| file_result(dent, options))
+                    .collect(),
+                verified,
+                part: if split { Some(i + 1) } else { None },
+                cross_device: dents
+                    .iter()
+                    .filter_map(|dent| dent.dev())
+                    .collect::<HashSet<_>>()
+                    .len()
+                    > 1,
+            });
+        }
+    }
+    KeyGroupResult {
+        size: key.size,
+        identifier: key.extension.to_string(),
+        hash_groups,
+        distinct: hashes
+            .distinct
+            .iter()
+            .map(|dent| file_result(dent, options))
+            .collect(),
+        n_files: dents.len() as u64,
+    }
+}
+
+fn print_key_group_result(
+    stream: &mut dyn Write,
+    kgr: &KeyGroupResult,
+) -> Result<(), Box<dyn Error>> {
+    if !kgr.hash_groups.iter().any(|hg| hg.files.len() > 1) {
+        return Ok(());
+    }
+    let size = format_size(kgr.size, DECIMAL);
+
+    for hg in &kgr.hash_groups {
+        let n_files = hg.files.len();
+        if n_files <= 1 {
+            continue;
+        }
+        writeln!(
+            stream,
+            "### {} / {} / {} ({} files{})",
+            size,
+            kgr.identifier,
+            hg.label(),
+            n_files,
+            if hg.cross_device {
+                ", cross-device"
+            } else {
+                ""
+            },
+        )?;
+        if let Some(keeper) = &hg.keeper {
+            write!(stream, "# keep: ")?;
+            write_path_line(stream, keeper)?;
+        }
+        for file in &hg.files {
+            write_path_line(stream, &file.path)?;
+            for link in &file.symlinks {
+                write!(stream, "# symlink: ")?;
+                write_path_line(stream, link)?;
+            }
+        }
+        writeln!(stream)?;
+    }
+    Ok(())
+}
+
+fn print_dir_group_result(
+    stream: &mut dyn Write,
+    dgr: &DirGroupResult,
+) -> Result<(), Box<dyn Error>> {
+    let size = dgr.dirs.iter().map(|dir| dir.size).max().unwrap_or(0);
+    if dgr.identical {
+        writeln!(
+            stream,
+            "### {} / directories ({} dirs, {} files each)",
+            format_size(size, DECIMAL),
+            dgr.dirs.len(),
+            dgr.dirs[0].n_files,
+        )?;
+        for dir in &dgr.dirs {
+            write_path_line(stream, &dir.path)?;
+        }
+    } else {
+        writeln!(
+            stream,
+            "### {} / similar directories ({:.1}% shared)",
+            format_size(size, DECIMAL),
+            dgr.similarity * 100.0,
+        )?;
+        for dir in &dgr.dirs {
+            writeln!(
+                stream,
+                "# {} files, {}",
+                dir.n_files,
+                format_size(dir.size, DECIMAL)
+            )?;
+            write_path_line(stream, &dir.path)?;
+        }
+    }
+    writeln!(stream)?;
+    Ok(())
+}
+
+fn get_hardlink_set_results(hardlinked: &[AugDirEntry]) -> Vec<HardlinkSetResult> {
+    let mut results: Vec<HardlinkSetResult> = hardlinked
+        .iter()
+        .map(|dent| HardlinkSetResult {
+            size: dent.size,
+            files: std::iter::once(dent.path())
+                .chain(dent.links.iter().map(|link| link.as_path()))
+                .map(ReportPath::new)
+                .collect(),
+        })
+        .collect();
+    results.sort_unstable_by(|a, b| b.size.cmp(&a.size).then_with(|| a.files.cmp(&b.files)));
+    results
+}
+
+fn print_hardlink_set_result(
+    stream: &mut dyn Write,
+    hsr: &HardlinkSetResult,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(
+        stream,
+        "### {} / hardlinks ({} files, 0 B wasted)",
+        format_size(hsr.size, DECIMAL),
+        hsr.files.len()
+    )?;
+    for path in &hsr.files {
+        write_path_line(stream, path)?;
+    }
+    writeln!(stream)?;
+    Ok(())
+}
+
+fn print_dangling_symlinks(
+    stream: &mut dyn Write,
+    dangling_symlinks: &[ReportPath],
+) -> Result<(), Box<dyn Error>> {
+    if dangling_symlinks.is_empty() {
+        return Ok(());
+    }
+    writeln!(
+        stream,
+        "### dangling symlinks ({})",
+        dangling_symlinks.len()
+    )?;
+    for path in dangling_symlinks {
+        write_path_line(stream, path)?;
+    }
+    writeln!(stream)?;
+    Ok(())
+}
+
+fn do_hash(
+    options: &mut Options,
+    by_key: &KeyToDentsMap,
+    cache: Option<&HashCache>,
+) -> Vec<KeyGroupResult> {
+    let mut sorted_pairs = by_key
+        .iter()
+        .collect::<Vec<(&GroupKey, &Vec<AugDirEntry>)>>();
+    sorted_pairs.sort_unstable_by(|(a, _), (b, _)| {
+        b.size
+            .cmp(&a.size)
+            .then_with(|| a.extension.as_ref().cmp(b.extension.as_ref()))
+    });
+    let prog = ProgressBar::new(sorted_pairs.len() as u64);
+    prog.set_style(
+        ProgressStyle::default_bar()
+            .template("{pos:>6}/{len:6} {msg} (ETA {eta}) {wide_bar}")
+            .unwrap(),
+    );
+
+    let key_group_results: Vec<KeyGroupResult> = sorted_pairs
+        .par_iter()
+        .map(|(key, dents)| {
+            if is_interrupted() {
+                return None;
+            }
+            prog.set_message(format!("{}/{}", key.extension, key.size));
+            prog.inc(1);
+            Some(process_key_group(key, dents, options, cache))
+        })
+        .filter_map(|x| x)
+        .collect();
+    prog.finish();
+    key_group_results
+}
+
+fn print_stage_duration(label: &str, hash_stats: &HashStats, d: Duration) {
+    let time = d.as_secs_f32();
+    let files_per_sec = (hash_stats.n_files as f32 / time) as u32;
+    let bytes_per_sec = ((hash_stats.n_bytes) as f32 / time) as u32;
+
+    eprintln!(
+        "{}: {} seconds ({} files/sec, {}/sec).",
+        label,
+        time,
+        files_per_sec,
+        format_size(bytes_per_sec, DECIMAL),
+    );
+}
+
+fn print_duplicate_info(key_group_results: &[KeyGroupResult]) {
+    let mut n_duplicate_files: u64 = 0;
+    let mut n_bytes_wasted: u64 = 0;
+    for kgr in key_group_results.iter() {
+        for hg in &kgr.hash_groups {
+            if hg.files.len() > 1 {
+                let n = (hg.files.len() - 1) as u64;
+                n_duplicate_files += n;
+                n_bytes_wasted += kgr.size * n;
+            }
+        }
+    }
+    if n_duplicate_files > 0 {
+        eprintln!(
+            "{} duplicate files, {} wasted.",
+            n_duplicate_files,
+            format_size(n_bytes_wasted, DECIMAL),
+        );
+    } else {
+        eprintln!("No duplicates.");
+    }
+}
+
+pub fn print_action_stats(action: &Action, dry_run: bool, action_stats: &ActionStats) {
+    let past_verb = action.past_verb();
+    let done = if dry_run {
+        format!("Would have {}", past_verb)
+    } else {
+        past_verb[..1].to_uppercase() + &past_verb[1..]
+    };
+    eprintln!(
+        "{} {} files in {} groups ({} skipped, {} failed), {} reclaimed.",
+        done,
+        action_stats.n_files,
+        action_stats.n_groups,
+        action_stats.n_skipped,
+        action_stats.n_failed,
+        format_size(action_stats.n_bytes_reclaimed, DECIMAL),
+    );
+}
+
+/// Print every found file, including the hardlinks hashed along with them.
+fn print_file_list(writer: &mut dyn Write, ksdmap: &KeyToPathToDentMap) -> io::Result<()> {
+    let mut paths: Vec<&PathBuf> = ksdmap
+        .values()
+        .flat_map(|by_path| by_path.iter())
+        .filter(|(_, dent)| !dent.external)
+        .flat_map(|(path, dent)| std::iter::once(path).chain(&dent.links))
+        .collect();
+    paths.sort_unstable();
+    for path in paths {
+        write_path_line(writer, path)?;
+    }
+    Ok(())
+}
+
+/// Paths of files with no duplicate: those culled before hashing (which,
+/// outside `--reference` roots, were alone in their key group), those told
+/// apart by a partial hash, and those alone in their hash group.
+fn unique_paths<'a>(
+    culled: &'a [AugDirEntry],
+    key_groups: &'a [KeyGroupResult],
+    options: &Options,
+) -> Vec<&'a Path> {
+    let culled = culled
+        .iter()
+        .filter(|dent| !dent.is_reference(options))
+        .map(|dent| dent.path());
+    let hashed = key_groups.iter().flat_map(|kgr| {
+        kgr.hash_groups
+            .iter()
+            .filter(|hg| hg.files.len() == 1)
+            .map(|hg| &hg.files[0])
+            .chain(&kgr.distinct)
+            .filter(|file| !file.reference)
+            .map(|file| &*file.path)
+    });
+    let is_wanted = |path: &Path| {
+        // `--unique-under` directories are canonical when there are no roots.
+        let path = match &options.files_from {
+            Some(_) => Cow::Owned(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())),
+            None => Cow::Borrowed(path),
+        };
+        options.unique_under.iter().any(|dir| path.starts_with(dir))
+    };
+    let mut paths: Vec<&Path> = culled
+        .chain(hashed)
+        .filter(|path| options.unique_under.is_empty() || is_wanted(path))
+        .collect();
+    paths.sort_unstable();
+    paths
+}
+
+fn maybe_write_report<W>(report_option: &ReportOption, writer: W) -> anyhow::Result<()>
+where
+    W: FnOnce(&mut dyn Write) -> Result<(), Box<dyn Error>>,
+{
+    let (name, mut stream): (&str, Box<dyn Write>) = match report_option {
+        ReportOption::None => return Ok(()),
+        ReportOption::Stdout => ("stdout", Box::new(stdout())),
+        ReportOption::File(name) => {
+            let file = File::create(name)
+                .map_err(|e| anyhow::anyhow!("Unable to create report {}: {}", name, e))?;
+            (name, Box::new(file))
+        }
+    };
+    writer(&mut *stream)
+        .and_then(|()| Ok(stream.flush()?))
+        .map_err(|e| anyhow::anyhow!("Unable to write report to {}: {}", name, e))
+}
+
+/// Find duplicates as `options` say, writing the reports asked for and
+/// acting on the duplicates if an action was given.
+///
+/// Stops early once `interrupt::INTERRUPTED` is set, e.g. by the handler
+/// `interrupt::configure_interrupt` installs.
+pub fn run(mut options: Options) -> anyhow::Result<()> {
+    if options.report_json == ReportOption::None
+        && options.report_human == ReportOption::None
+        && options.report_script == ReportOption::None
+        && options.report_unique == ReportOption::None
+    {
+        eprintln!("No output arguments set; assuming human output to stdout desired.");
+        options.report_human = ReportOption::Stdout;
+    }
+    let start_time = Instant::now();
+    let FindResult {
+        find_stats,
+        mut hash_stats,
+        by_key,
+        precull_files,
+        hardlinked,
+        dangling_symlinks,
+        culled,
+    } = find_files(&options, options.report_file_list != ReportOption::None)?;
+    eprintln!(
+        "Found {} files in {} directories ({} groups before culling) in {:.2} s, {}.",
+        find_stats.n_files,
+        find_stats.n_dirs,
+        find_stats.n_precull_groups,
+        start_time.elapsed().as_secs_f32(),
+        format_size(find_stats.n_bytes, DECIMAL),
+    );
+    if find_stats.n_hardlinks > 0 {
+        eprintln!(
+            "Found {} additional hardlinks to {} files; hashing each file once.",
+            find_stats.n_hardlinks,
+            hardlinked.len(),
+        );
+    }
+    if find_stats.n_symlinks > 0 {
+        match options.symlinks {
+            SymlinkOption::Follow => eprintln!(
+                "Reached {} files again through symlinks; hashing each file once.",
+                find_stats.n_symlinks,
+            ),
+            _ => eprintln!(
+                "Found {} symlinks to files, reported with their targets.",
+                find_stats.n_symlinks
+            ),
+        }
+    }
+    if !dangling_symlinks.is_empty() {
+        eprintln!("Found {} dangling symlinks.", dangling_symlinks.len());
+    }
+    let hardlink_sets = match options.hardlinks {
+        HardlinkOption::List => get_hardlink_set_results(&hardlinked),
+        HardlinkOption::Ignore => Vec::new(),
+    };
+    if precull_files.is_some() {
+        maybe_write_report(&options.report_file_list, |stream| {
+            Ok(print_file_list(stream, precull_files.as_ref().unwrap())?)
+        })?;
+    }
+    eprintln!(
+        "Hashing {} groups, {} files, {}.",
+        hash_stats.n_groups,
+        hash_stats.n_files,
+        format_size(hash_stats.n_bytes, DECIMAL),
+    );
+    let hash_start_time = Instant::now();
+    let cache = options
+        .hash_cache
+        .as_ref()
+        .map(|path| HashCache::load(Path::new(path)));
+    let mut key_group_results = do_hash(&mut options, &by_key, cache.as_ref());
+    hash_stats.interrupted = check_and_reset_interrupt();
+    if let Some(cache) = &cache {
+        hash_stats.n_cache_hits = cache.n_hits();
+        hash_stats.n_cache_misses = cache.n_misses();
+        eprintln!(
+            "Hash cache: {} hits, {} misses.",
+            hash_stats.n_cache_hits, hash_stats.n_cache_misses
+        );
+        match cache.save() {
+            Ok(n_pruned) if n_pruned > 0 => {
+                eprintln!("Pruned {} stale hash cache entries.", n_pruned)
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("[!] Unable to save hash cache: {}", e),
+        }
+    }
+    print_stage_duration("Hashing", &hash_stats, hash_start_time.elapsed());
+    let dir_groups = match options.duplicate_dirs {
+        DuplicateDirsOption::Off => Vec::new(),
+        _ => find_duplicate_dirs(&by_key, &culled, &key_group_results, &options),
+    };
+    if !dir_groups.is_empty() {
+        let n_identical = dir_groups.iter().filter(|g| g.identical).count();
+        eprintln!(
+            "Found {} groups of identical directories and {} pairs of similar ones.",
+            n_identical,
+            dir_groups.len() - n_identical,
+        );
+    }
+    if options.duplicate_dirs == DuplicateDirsOption::Instead {
+        drop_files_in_duplicate_dirs(&mut key_group_results, &dir_groups);
+    }
+    let gr = GrandResult {
+        version: REPORT_VERSION,
+        hash_algorithm: options.hash_algorithm,
+        hash_bytes: options.hash_bytes,
+        find_stats,
+        hash_stats,
+        key_groups: key_group_results,
+        hardlink_sets,
+        dangling_symlinks: dangling_symlinks
+            .iter()
+            .map(|p| ReportPath::new(p))
+            .collect(),
+        dir_groups,
+    };
+    let output_start_time = Instant::now();
+    maybe_write_report(&options.report_human, |stream| {
+        for dgr in gr.dir_groups.iter() {
+            print_dir_group_result(stream, dgr)?;
+        }
+        for kgr in gr.key_groups.iter() {
+            print_key_group_result(stream, kgr)?;
+        }
+        for hsr in gr.hardlink_sets.iter() {
+            print_hardlink_set_result(stream, hsr)?;
+        }
+        print_dangling_symlinks(stream, &gr.dangling_symlinks)
+    })?;
+    maybe_write_report(&options.report_json, |stream| {
+        Ok(serde_json::to_writer_pretty(stream, &gr)?)
+    })?;
+    maybe_write_report(&options.report_script, |stream| {
+        write_script(stream, &gr.key_groups, &options.script_action)
+    })?;
+    if options.report_unique != ReportOption::None {
+        let unique = unique_paths(&culled, &gr.key_groups, &options);
+        maybe_write_report(&options.report_unique, |stream| {
+            for path in &unique {
+                write_path_line(stream, path)?;
+            }
+            Ok(())
+        })?;
+        eprintln!("{} unique files.", unique.len());
+    }
+    print_duplicate_info(&gr.key_groups);
+    print_stage_duration("Output", &gr.hash_stats, output_start_time.elapsed());
+    if options.action.is_some() && (gr.find_stats.interrupted || gr.hash_stats.interrupted) {
+        eprintln!("Not acting on duplicates, as the search was interrupted.");
+    } else if let Some(action) = &options.action {
+        let action_stats = act_on_duplicates(
+            &gr.key_groups,
+            action,
+            options.dry_run,
+            Path::new(&options.journal),
+        )?;
+        print_action_stats(action, options.dry_run, &action_stats);
+    }
+    print_stage_duration("Finished", &gr.hash_stats, start_time.elapsed());
+    Ok(())
+}
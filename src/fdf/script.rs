@@ -0,0 +1,226 @@
+use super::options::ActionKind;
+use super::output::KeyGroupResult;
+use super::report_path::path_to_bytes;
+use humansize::{format_size, DECIMAL};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const SCRIPT_HEADER: &str = r#"#!/bin/sh
+# Review this script before running it!
+#
+# Each action first checks that both files still exist, still have the size
+# they had when scanned, and are still identical; otherwise the file is skipped.
+
+set -u
+
+# Line breaks in file names are spliced in from these, so each command fits on one line.
+fdf_nl='
+'
+fdf_cr=$(printf '\r')
+
+fdf_size() {
+    wc -c < "$1" | tr -d ' '
+}
+
+fdf_check() {
+    if [ ! -f "$1" ] || [ ! -f "$2" ]; then
+        echo "skipping $2: missing" >&2
+        return 1
+    fi
+    if [ "$(fdf_size "$1")" != "$3" ] || [ "$(fdf_size "$2")" != "$3" ]; then
+        echo "skipping $2: size has changed" >&2
+        return 1
+    fi
+    if ! cmp -s "$1" "$2"; then
+        echo "skipping $2: contents differ" >&2
+        return 1
+    fi
+}
+
+# Usage: fdf_rm KEPT FILE SIZE
+fdf_rm() {
+    fdf_check "$@" && rm -f -- "$2"
+}
+
+# Usage: fdf_replace FILE COMMAND [ARG...]
+# Runs COMMAND ARG... TEMP with a temporary name next to FILE, and only if that
+# works renames TEMP over FILE, so FILE is never left half-replaced.
+fdf_replace() {
+    case $1 in
+        */*) fdf_tmp="${1%/*}/.fdf-tmp-$$" ;;
+        *) fdf_tmp=".fdf-tmp-$$" ;;
+    esac
+    fdf_file=$1
+    shift
+    if [ -e "$fdf_tmp" ] || [ -L "$fdf_tmp" ]; then
+        echo "skipping $fdf_file: $fdf_tmp is in the way" >&2
+        return 1
+    fi
+    if "$@" "$fdf_tmp" && mv -f -- "$fdf_tmp" "$fdf_file"; then
+        return 0
+    fi
+    rm -f -- "$fdf_tmp"
+    return 1
+}
+
+# Usage: fdf_ln KEPT FILE SIZE
+fdf_ln() {
+    fdf_check "$@" && fdf_replace "$2" ln -- "$1"
+}
+
+# Usage: fdf_symlink KEPT FILE SIZE
+fdf_symlink() {
+    fdf_check "$@" && fdf_replace "$2" ln -s -- "$1"
+}
+
+# Usage: fdf_reflink KEPT FILE SIZE (requires GNU cp)
+fdf_reflink() {
+    fdf_check "$@" && fdf_replace "$2" cp --reflink=always -- "$1"
+}
+"#;
+
+/// Quote a path for the shell, keeping its raw bytes so non-UTF-8 names survive.
+///
+/// Line breaks are spliced in from variables defined in the script header,
+/// so the quoted path never spans lines and is safe to put in a comment.
+pub fn shell_quote(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &b in path_to_bytes(path).iter() {
+        match b {
+            b'\'' => quoted.extend_from_slice(br"'\''"),
+            b'\n' => quoted.extend_from_slice(br#"'"$fdf_nl"'"#),
+            b'\r' => quoted.extend_from_slice(br#"'"$fdf_cr"'"#),
+            _ => quoted.push(b),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+pub fn write_script(
+    stream: &mut dyn Write,
+    key_group_results: &[KeyGroupResult],
+    action: &ActionKind,
+) -> Result<(), Box<dyn Error>> {
+    let function = match action {
+        ActionKind::Delete => "fdf_rm",
+        ActionKind::Hardlink => "fdf_ln",
+        ActionKind::Symlink => "fdf_symlink",
+        ActionKind::Reflink => "fdf_reflink",
+    };
+    write!(stream, "{}", SCRIPT_HEADER)?;
+    for kgr in key_group_results {
+        for hg in kgr.hash_groups.iter().filter(|hg| hg.files.len() > 1) {
+            let keeper = hg.keeper.as_ref().unwrap_or(&hg.files[0].path);
+            // Symlinks resolve relative to where they are, so give them the
+            // kept file's absolute path, as `--link symlink` does.
+            let target: Cow<Path> = match action {
+                ActionKind::Symlink => {
+                    fs::canonicalize(keeper).map_or(Cow::Borrowed(keeper.as_ref()), Cow::Owned)
+                }
+                _ => Cow::Borrowed(keeper.as_ref()),
+            };
+            writeln!(stream)?;
+            writeln!(
+                stream,
+                "# {} / {} / {} ({} files{}{})",
+                format_size(kgr.size, DECIMAL),
+                kgr.identifier.replace(['\n', '\r'], "?"),
+                hg.label(),
+                hg.files.len(),
+                if hg.verified { ", verified" } else { "" },
+                if hg.cross_device {
+                    ", cross-device"
+                } else {
+                    ""
+                },
+            )?;
+            write!(stream, "# keep: ")?;
+            stream.write_all(&shell_quote(keeper))?;
+            writeln!(stream)?;
+            for file in hg
+                .files
+                .iter()
+                .filter(|file| file.path != *keeper && !file.reference)
+            {
+                write!(stream, "{} ", function)?;
+                stream.write_all(&shell_quote(&target))?;
+                write!(stream, " ")?;
+                stream.write_all(&shell_quote(&file.path))?;
+                writeln!(stream, " {}", kgr.size)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::FileResult;
+    use std::process::Command;
+
+    fn file_result(path: &str) -> FileResult {
+        FileResult::for_test(Path::new(path), Some(Path::new("/data")))
+    }
+
+    #[test]
+    fn line_breaks_in_names_stay_out_of_commands() {
+        let kgr = KeyGroupResult {
+            identifier: "txt\nrm -rf /".to_string(),
+            ..KeyGroupResult::for_test(
+                4,
+                vec![vec![
+                    file_result("/data/a\nrm -rf /\n.txt"),
+                    file_result("/data/b\r\nrm -rf /.txt"),
+                ]],
+            )
+        };
+        let mut script = Vec::new();
+        write_script(&mut script, &[kgr], &ActionKind::Delete).unwrap();
+        let script = String::from_utf8(script).unwrap();
+        let body = script.strip_prefix(SCRIPT_HEADER).unwrap();
+        for line in body.lines() {
+            assert!(
+                line.is_empty() || line.starts_with('#') || line.starts_with("fdf_rm "),
+                "unexpected line {:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn failed_reflinks_leave_files_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = ["a.txt", "b.txt"].map(|name| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, "data").unwrap();
+            FileResult::for_test(&path, Some(dir.path()))
+        });
+        let kgr = KeyGroupResult::for_test(4, vec![files.into()]);
+        let script_path = dir.path().join("script.sh");
+        let mut script = std::fs::File::create(&script_path).unwrap();
+        write_script(&mut script, &[kgr], &ActionKind::Reflink).unwrap();
+        // Whether cp can clone files here or not, b.txt must keep its data.
+        Command::new("sh").arg(&script_path).output().unwrap();
+        assert_eq!(std::fs::read(dir.path().join("b.txt")).unwrap(), b"data");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn quoted_paths_expand_to_themselves() {
+        let path = "/data/it's a\nfile\r";
+        let mut command = SCRIPT_HEADER.as_bytes().to_vec();
+        command.extend_from_slice(b"printf %s ");
+        command.extend_from_slice(&shell_quote(Path::new(path)));
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(String::from_utf8(command).unwrap())
+            .output()
+            .unwrap();
+        assert_eq!(output.stdout, path.as_bytes());
+    }
+}
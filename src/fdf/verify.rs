@@ -0,0 +1,84 @@
+use super::find::AugDirEntry;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+const CHUNK_SIZE: usize = 65_536;
+
+/// Read until `buf` is full or the reader is exhausted.
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Compare the full contents of two files.
+pub fn files_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut ra = BufReader::with_capacity(CHUNK_SIZE, File::open(a)?);
+    let mut rb = BufReader::with_capacity(CHUNK_SIZE, File::open(b)?);
+    let mut buf_a = vec![0u8; CHUNK_SIZE];
+    let mut buf_b = vec![0u8; CHUNK_SIZE];
+    loop {
+        let na = read_chunk(&mut ra, &mut buf_a)?;
+        let nb = read_chunk(&mut rb, &mut buf_b)?;
+        if na != nb || buf_a[..na] != buf_b[..nb] {
+            return Ok(false);
+        }
+        if na == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// A group of supposedly identical files, compared byte-for-byte.
+pub struct VerifiedGroup<'a> {
+    /// Groups of files that really are identical.
+    pub groups: Vec<Vec<&'a AugDirEntry>>,
+    /// Files that couldn't be read, so are unverified.
+    pub unreadable: Vec<&'a AugDirEntry>,
+}
+
+/// Split a group of supposedly identical files into groups of files
+/// that really are identical, by comparing each member against the first one.
+pub fn verify_group<'a>(dents: &[&'a AugDirEntry]) -> VerifiedGroup<'a> {
+    let mut groups = Vec::new();
+    let mut unreadable = Vec::new();
+    let mut remaining = dents.to_vec();
+    while !remaining.is_empty() {
+        let first = remaining.remove(0);
+        // Make sure errors comparing against it come from the other file.
+        if let Err(x) = File::open(first.path()) {
+            eprintln!("[!] Unable to verify {}: {}", first.path().display(), x);
+            unreadable.push(first);
+            continue;
+        }
+        let mut group = vec![first];
+        let mut mismatched = Vec::new();
+        for dent in remaining {
+            match files_equal(first.path(), dent.path()) {
+                Ok(true) => group.push(dent),
+                Ok(false) => mismatched.push(dent),
+                Err(x) => {
+                    eprintln!(
+                        "[!] Unable to verify {} against {}: {}",
+                        dent.path().display(),
+                        first.path().display(),
+                        x
+                    );
+                    unreadable.push(dent);
+                }
+            }
+        }
+        groups.push(group);
+        remaining = mismatched;
+    }
+    VerifiedGroup { groups, unreadable }
+}
@@ -0,0 +1,358 @@
+use super::find::FileId;
+use super::interrupt::is_interrupted;
+use super::mounts::excluded_devices;
+use super::options::{Options, SymlinkOption};
+use super::report_path::bytes_to_path;
+use humansize::{format_size, DECIMAL};
+use ignore::{DirEntry, ParallelVisitor, ParallelVisitorBuilder, WalkBuilder, WalkState};
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::{File, Metadata};
+use std::io::{stdin, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Something the walker found, before results from all threads are merged.
+pub enum Found {
+    File {
+        /// Index of the `--directory` root this was found under.
+        root: usize,
+        path: PathBuf,
+        size: u64,
+        mtime: Option<SystemTime>,
+        id: Option<FileId>,
+    },
+    /// With `--symlinks report`, a symlink pointing at a regular file.
+    Symlink {
+        path: PathBuf,
+        target: FileId,
+    },
+    Dangling(PathBuf),
+}
+
+impl Found {
+    /// Files by root and path, then symlinks and dangling symlinks by path.
+    pub fn sort_key(&self) -> (u8, usize, &Path) {
+        match self {
+            Found::File { root, path, .. } => (0, *root, path),
+            Found::Symlink { path, .. } => (1, 0, path),
+            Found::Dangling(path) => (2, 0, path),
+        }
+    }
+}
+
+pub struct WalkResult {
+    pub found: Vec<Found>,
+    pub n_dirs: u64,
+}
+
+/// Number of entries a thread collects before handing them over and updating progress.
+const BATCH_SIZE: usize = 1024;
+
+struct WalkContext<'a> {
+    options: &'a Options,
+    excluded_devs: HashSet<u64>,
+    prog: &'a ProgressBar,
+    n_dirs: AtomicU64,
+    n_files: AtomicU64,
+    n_bytes: AtomicU64,
+    found: Mutex<Vec<Found>>,
+}
+
+impl WalkContext<'_> {
+    fn is_on_excluded_device(&self, entry: &DirEntry) -> bool {
+        !self.excluded_devs.is_empty()
+            && entry
+                .metadata()
+                .ok()
+                .and_then(|meta| FileId::from_metadata(&meta))
+                .is_some_and(|id| self.excluded_devs.contains(&id.dev))
+    }
+}
+
+struct FindVisitor<'a> {
+    ctx: &'a WalkContext<'a>,
+    found: Vec<Found>,
+    n_dirs: u64,
+    n_files: u64,
+    n_bytes: u64,
+}
+
+impl FindVisitor<'_> {
+    fn push(&mut self, found: Found) {
+        self.found.push(found);
+        if self.found.len() >= BATCH_SIZE {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        let ctx = self.ctx;
+        let n_dirs = ctx.n_dirs.fetch_add(self.n_dirs, Ordering::Relaxed) + self.n_dirs;
+        let n_files = ctx.n_files.fetch_add(self.n_files, Ordering::Relaxed) + self.n_files;
+        let n_bytes = ctx.n_bytes.fetch_add(self.n_bytes, Ordering::Relaxed) + self.n_bytes;
+        ctx.prog.inc(self.n_files);
+        ctx.prog.set_message(format!(
+            "{} dirs, {} files, {}...",
+            n_dirs,
+            n_files,
+            format_size(n_bytes, DECIMAL)
+        ));
+        (self.n_dirs, self.n_files, self.n_bytes) = (0, 0, 0);
+        ctx.found.lock().unwrap().append(&mut self.found);
+    }
+}
+
+/// With `--symlinks report`, what to record for an unfollowed symlink.
+fn found_symlink(path: &Path) -> Option<Found> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_file() => FileId::from_metadata(&meta).map(|target| Found::Symlink {
+            path: path.to_path_buf(),
+            target,
+        }),
+        Ok(_) => None,
+        Err(_) => Some(Found::Dangling(path.to_path_buf())),
+    }
+}
+
+/// Whether a file's size and metadata make it a candidate for deduplication.
+fn is_candidate(options: &Options, metadata: &Metadata) -> bool {
+    let size = metadata.len();
+    size != 0
+        && size >= options.min_size
+        && size <= options.max_size
+        && options.is_metadata_included(metadata)
+}
+
+impl Drop for FindVisitor<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// The path an error from the walker is about, if any.
+fn error_path(err: &ignore::Error) -> Option<&Path> {
+    match err {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            error_path(err)
+        }
+        _ => None,
+    }
+}
+
+fn is_dangling_symlink(path: &Path) -> bool {
+    path.symlink_metadata()
+        .is_ok_and(|meta| meta.file_type().is_symlink())
+        && fs::metadata(path).is_err()
+}
+
+impl ParallelVisitor for FindVisitor<'_> {
+    fn visit(&mut self, result: Result<DirEntry, ignore::Error>) -> WalkState {
+        if is_interrupted() {
+            return WalkState::Quit;
+        }
+        let options = self.ctx.options;
+        let entry = match result {
+            Ok(entry) => entry,
+            Err(err) => {
+                let follow = options.symlinks == SymlinkOption::Follow;
+                match error_path(&err).filter(|path| follow && is_dangling_symlink(path)) {
+                    Some(path) => self.push(Found::Dangling(path.to_path_buf())),
+                    None => eprintln!("[!] {}", err),
+                }
+                return WalkState::Continue;
+            }
+        };
+        let Some(file_type) = entry.file_type() else {
+            return WalkState::Continue;
+        };
+        let root = options.root_index(entry.path());
+        if !options.is_entry_included(entry.path(), file_type.is_dir(), root) {
+            return WalkState::Skip;
+        }
+        if file_type.is_dir() {
+            if self.ctx.is_on_excluded_device(&entry) {
+                return WalkState::Skip;
+            }
+            self.n_dirs += 1;
+            return WalkState::Continue;
+        }
+        if file_type.is_symlink() {
+            // Only reached when not following symlinks.
+            if options.symlinks == SymlinkOption::Report {
+                if let Some(found) = found_symlink(entry.path()) {
+                    self.push(found);
+                }
+            }
+            return WalkState::Continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                eprintln!("[!] {}", err);
+                return WalkState::Continue;
+            }
+        };
+        if !is_candidate(options, &metadata) {
+            return WalkState::Continue;
+        }
+        let size = metadata.len();
+        self.n_files += 1;
+        self.n_bytes += size;
+        self.push(Found::File {
+            root,
+            path: entry.into_path(),
+            size,
+            mtime: metadata.modified().ok(),
+            id: FileId::from_metadata(&metadata),
+        });
+        WalkState::Continue
+    }
+}
+
+struct FindVisitorBuilder<'a> {
+    ctx: &'a WalkContext<'a>,
+}
+
+impl<'s> ParallelVisitorBuilder<'s> for FindVisitorBuilder<'s> {
+    fn build(&mut self) -> Box<dyn ParallelVisitor + 's> {
+        Box::new(FindVisitor {
+            ctx: self.ctx,
+            found: Vec::with_capacity(BATCH_SIZE),
+            n_dirs: 0,
+            n_files: 0,
+            n_bytes: 0,
+        })
+    }
+}
+
+/// Walk all roots in parallel, returning what was found in no particular order.
+pub fn walk(options: &Options, prog: &ProgressBar) -> WalkResult {
+    let ctx = WalkContext {
+        options,
+        excluded_devs: excluded_devices(&options.exclude_fs_types),
+        prog,
+        n_dirs: AtomicU64::new(0),
+        n_files: AtomicU64::new(0),
+        n_bytes: AtomicU64::new(0),
+        found: Mutex::new(Vec::new()),
+    };
+    let Some((first, rest)) = options.directories.split_first() else {
+        return WalkResult {
+            found: Vec::new(),
+            n_dirs: 0,
+        };
+    };
+    let mut builder = WalkBuilder::new(first);
+    for dir in rest {
+        builder.add(dir);
+    }
+    // Only ignore files are honored, not hidden files or global git excludes;
+    // `.gitignore` files only apply within git repositories (`require_git`).
+    builder
+        .standard_filters(false)
+        .git_ignore(options.ignore_files)
+        .require_git(true)
+        .ignore(options.ignore_files)
+        .parents(options.ignore_files);
+    if options.ignore_files {
+        builder.add_custom_ignore_filename(".fdfignore");
+    }
+    builder
+        .follow_links(options.symlinks == SymlinkOption::Follow)
+        .same_file_system(options.one_file_system)
+        .threads(options.threads)
+        .build_parallel()
+        .visit(&mut FindVisitorBuilder { ctx: &ctx });
+    WalkResult {
+        n_dirs: ctx.n_dirs.into_inner(),
+        found: ctx.found.into_inner().unwrap(),
+    }
+}
+
+/// Stat a path given with `--files-from`, applying the same filters as the walker.
+fn found_listed(options: &Options, path: PathBuf) -> Option<Found> {
+    let root = 0;
+    // There's no walk to prune directories, so check the one the file is in.
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    if parent.is_some_and(|parent| !options.is_entry_included(parent, true, root))
+        || !options.is_entry_included(&path, false, root)
+    {
+        return None;
+    }
+    let mut metadata = match fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            eprintln!("[!] {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    if metadata.file_type().is_symlink() {
+        match options.symlinks {
+            SymlinkOption::Skip => return None,
+            SymlinkOption::Report => return found_symlink(&path),
+            SymlinkOption::Follow => match fs::metadata(&path) {
+                Ok(target) => metadata = target,
+                Err(_) => return Some(Found::Dangling(path)),
+            },
+        }
+    }
+    if !is_candidate(options, &metadata) {
+        return None;
+    }
+    Some(Found::File {
+        root,
+        size: metadata.len(),
+        mtime: metadata.modified().ok(),
+        id: FileId::from_metadata(&metadata),
+        path,
+    })
+}
+
+/// Read the paths to consider from `source` (a file, or `-` for stdin) instead of walking.
+///
+/// Paths are separated by newlines (or CRLF), or by NULs with `--null`.
+pub fn read_file_list(
+    options: &Options,
+    source: &str,
+    prog: &ProgressBar,
+) -> anyhow::Result<WalkResult> {
+    let mut data = Vec::new();
+    let res = if source == "-" {
+        stdin().read_to_end(&mut data)
+    } else {
+        File::open(source).and_then(|mut f| f.read_to_end(&mut data))
+    };
+    res.map_err(|e| anyhow::anyhow!("Unable to read file list {}: {}", source, e))?;
+    let separator = if options.null_separated { b'\0' } else { b'\n' };
+    let paths: Vec<PathBuf> = data
+        .split(|b| *b == separator)
+        .map(|line| {
+            if options.null_separated {
+                line
+            } else {
+                line.strip_suffix(b"\r").unwrap_or(line)
+            }
+        })
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| bytes_to_path(line.to_vec()))
+        .collect();
+    prog.set_message(format!("Checking {} listed paths...", paths.len()));
+    let found = paths
+        .into_par_iter()
+        .filter_map(|path| {
+            if is_interrupted() {
+                return None;
+            }
+            found_listed(options, path)
+        })
+        .collect();
+    Ok(WalkResult { found, n_dirs: 0 })
+}
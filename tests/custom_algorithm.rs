@@ -0,0 +1,55 @@
+use fdf::cli::parse_args_from;
+use fdf::hasher::{register, FileHasher, HashAlgorithm};
+use fdf::options::Invocation;
+use std::fs;
+
+/// Sums the bytes hashed, which is plenty to tell apart the files below.
+struct ByteSum(u64);
+
+impl FileHasher for ByteSum {
+    fn update(&mut self, data: &[u8]) {
+        self.0 += data.iter().map(|&b| b as u64).sum::<u64>();
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+}
+
+#[test]
+fn finds_duplicates_with_a_registered_algorithm() {
+    register(HashAlgorithm {
+        name: "bytesum",
+        keyed: false,
+        new: |_| Box::new(ByteSum(0)),
+    });
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "same").unwrap();
+    fs::write(dir.path().join("b.txt"), "same").unwrap();
+    fs::write(dir.path().join("c.txt"), "diff").unwrap();
+    let report = dir.path().join("report.json");
+    let args = [
+        "fdf".as_ref(),
+        "--directory".as_ref(),
+        dir.path().as_os_str(),
+        "--hash-algorithm".as_ref(),
+        "bytesum".as_ref(),
+        "--output-json".as_ref(),
+        report.as_os_str(),
+    ];
+    let Invocation::Find(options) = parse_args_from(args).unwrap() else {
+        panic!("expected a find invocation");
+    };
+    fdf::run(*options).unwrap();
+
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    assert_eq!(report["hash_algorithm"], "bytesum");
+    let groups = report["key_groups"][0]["hash_groups"].as_array().unwrap();
+    let sum: u64 = "same".bytes().map(|b| b as u64).sum();
+    let duplicates: Vec<_> = groups
+        .iter()
+        .filter(|hg| hg["files"].as_array().unwrap().len() > 1)
+        .collect();
+    assert_eq!(duplicates.len(), 1);
+    assert_eq!(duplicates[0]["hash"], format!("bytesum-{:016x}", sum));
+}